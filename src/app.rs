@@ -1,32 +1,75 @@
-use crate::compositor::{dev::GpuHandle, tex::GpuTexture};
-use crate::compositor::{BufferDimensions, CompositorTarget};
-use crate::compositor::{CompositeLayer, CompositorPipeline};
-use crate::procreate::{ProcreateError, ProcreateFile, SilicaHierarchy};
+use crate::compositor::dev::GpuHandle;
+use crate::compositor::mip::MipPipeline;
+use crate::compositor::tex::{GpuTexture, LayerTextures, StagingBuffers};
+use crate::compositor::CompositorTarget;
+use crate::compositor::{CompositeLayer, CompositorPipeline, PipelineOptions, Precision};
+use crate::export::{self, AlphaMode, Rect};
+use crate::procreate::{BlendingMode, LayerFilter, ProcreateError, ProcreateFile, SilicaHierarchy};
+use image::{DynamicImage, Rgba32FImage};
+use rayon::prelude::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::{ParallelSlice, ParallelSliceMut};
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
 use std::sync::Arc;
-use image::{ImageBuffer, Rgba};
+use wgpu::CommandEncoder;
 
 pub struct App {
     pub dev: Arc<GpuHandle>,
     pub pipeline: CompositorPipeline,
+    pub mip: MipPipeline,
 }
 
 impl App {
+    /// Default number of layers rendered and read back together.
+    pub const EXPORT_BATCH_SIZE: usize = 8;
+
     pub fn new(dev: GpuHandle) -> Self {
+        Self::with_precision(dev, Precision::default())
+    }
+
+    /// Create an app whose compositing happens at the given precision.
+    pub fn with_precision(dev: GpuHandle, precision: Precision) -> Self {
+        Self::with_options(
+            dev,
+            PipelineOptions {
+                precision,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Create an app whose compositing uses the given pipeline options.
+    pub fn with_options(dev: GpuHandle, options: PipelineOptions) -> Self {
         App {
-            pipeline: CompositorPipeline::new(&dev),
+            pipeline: CompositorPipeline::with_options(&dev, options),
+            mip: MipPipeline::new(&dev),
             dev: Arc::new(dev),
         }
     }
 
+    /// Layer textures to render from once the output of a target is
+    /// scaled by `scale` with [`CompositorTarget::scale_dimensions`].
+    /// Reductions beyond half size need downsampled copies of the layers,
+    /// which are returned. Otherwise this is `None`, and `textures` can be
+    /// rendered from as they are.
+    pub fn textures_for_scale(
+        &self,
+        textures: &LayerTextures,
+        scale: f32,
+    ) -> Option<LayerTextures> {
+        self.mip
+            .downsample(&self.dev, textures, MipPipeline::levels_for_scale(scale))
+    }
+
     #[allow(unused)]
     pub async fn load_file_from_bytes(
         &self,
         file: Vec<u8>,
-    ) -> Result<(ProcreateFile, GpuTexture, CompositorTarget), ProcreateError> {
-        let (file, gpu_textures) = ProcreateFile::open_from_bytes(file, &self.dev).unwrap();
+    ) -> Result<(ProcreateFile, LayerTextures, CompositorTarget), ProcreateError> {
+        let (file, gpu_textures) = ProcreateFile::open_from_bytes(file, &self.dev)?;
 
         let mut target = CompositorTarget::new(self.dev.clone());
+        target.set_precision(self.pipeline.precision());
 
         target
             .data
@@ -44,10 +87,11 @@ impl App {
     pub async fn load_file_from_path(
         &self,
         path: PathBuf,
-    ) -> Result<(ProcreateFile, GpuTexture, CompositorTarget), ProcreateError> {
-        let (file, gpu_textures) = ProcreateFile::open(path, &self.dev).unwrap();
+    ) -> Result<(ProcreateFile, LayerTextures, CompositorTarget), ProcreateError> {
+        let (file, gpu_textures) = ProcreateFile::open(path, &self.dev)?;
 
         let mut target = CompositorTarget::new(self.dev.clone());
+        target.set_precision(self.pipeline.precision());
 
         target
             .data
@@ -65,73 +109,822 @@ impl App {
     pub async fn extract_image_buffers(
         &self,
         file: &ProcreateFile,
-        textures: &GpuTexture,
-        mut target: CompositorTarget,
-    ) -> Vec<ImageBuffer<Rgba<u8>, Vec<u8>>> {
-        let new_layer_config = file.layers.clone();
-        let background = (!file.background_hidden).then_some(file.background_color);
+        textures: &LayerTextures,
+        target: CompositorTarget,
+    ) -> Result<Vec<DynamicImage>, ProcreateError> {
+        let mut export = self
+            .export_layers(file, textures, target)
+            .batched(Self::EXPORT_BATCH_SIZE);
+        let mut image_buffers = Vec::with_capacity(export.len());
+
+        while let Some(layer) = export.next_layer().await {
+            image_buffers.push(layer?.image);
+        }
+
+        Ok(image_buffers)
+    }
+
+    /// Export layers one at a time, so that each image can be encoded
+    /// and written out before the next one is read back.
+    ///
+    /// See also [`LayerExport::batched`] to read back several layers
+    /// at once.
+    pub fn export_layers<'a>(
+        &'a self,
+        file: &ProcreateFile,
+        textures: &'a LayerTextures,
+        target: CompositorTarget,
+    ) -> LayerExport<'a> {
+        self.export_selected_layers(file, textures, target, &LayerFilter::default())
+    }
 
-        let layers = App::linearize_silica_layers(&new_layer_config);
-        let mut image_buffers = Vec::new();
-
-        for unresolved_layer in &layers {
-            target.render(
-                &self.pipeline,
-                background,
-                &[unresolved_layer.clone()],
-                textures,
-            );
-
-            if let Some(texture) = target.output.as_ref() {
-                let copied_texture = texture.texture.clone(&self.dev);
-                let dim = BufferDimensions::from_extent(copied_texture.size);
-                let image_buffer = copied_texture.export_texture(&target.dev, dim).await;
-                image_buffers.push(image_buffer);
+    /// Same as [`App::export_layers`], but only for the layers selected
+    /// by `filter`.
+    pub fn export_selected_layers<'a>(
+        &'a self,
+        file: &ProcreateFile,
+        textures: &'a LayerTextures,
+        target: CompositorTarget,
+        filter: &LayerFilter,
+    ) -> LayerExport<'a> {
+        let layers = App::linearize_selected_layers(&file.layers, filter)
+            .into_iter()
+            .map(|(layer, info)| (vec![layer], info))
+            .collect();
+        LayerExport::new(self, file, textures, target, layers)
+    }
+
+    /// Export the pixels of every layer as they are stored, hidden layers
+    /// included, without the background and without applying opacity,
+    /// blending or clipping. This is what formats that keep layers
+    /// editable, such as Krita documents, need.
+    pub fn export_layer_pixels<'a>(
+        &'a self,
+        file: &ProcreateFile,
+        textures: &'a LayerTextures,
+        target: CompositorTarget,
+    ) -> LayerExport<'a> {
+        let layers = App::linearize_silica_layers_with_info(&file.layers, true)
+            .into_iter()
+            .map(|(layer, info)| {
+                let layer = CompositeLayer {
+                    texture: layer.texture,
+                    clipped: None,
+                    opacity: 1.0,
+                    blend: BlendingMode::Normal,
+                };
+                (vec![layer], info)
+            })
+            .collect();
+        let mut export = LayerExport::new(self, file, textures, target, layers);
+        export.background = None;
+        export
+    }
+
+    /// Export each group as one image, flattened the same way it is in the
+    /// full composite. `depth` 1 only exports the top level groups, 2 the
+    /// groups right inside them, and so on, while `None` exports groups
+    /// at every depth. Hidden groups and groups inside them are skipped,
+    /// unless `include_hidden` is set, in which case they are rendered as
+    /// if they were visible but still marked as hidden.
+    pub fn export_groups<'a>(
+        &'a self,
+        file: &ProcreateFile,
+        textures: &'a LayerTextures,
+        target: CompositorTarget,
+        depth: Option<usize>,
+        include_hidden: bool,
+    ) -> LayerExport<'a> {
+        let groups = App::linearize_groups(&file.layers, depth, include_hidden);
+        LayerExport::new(self, file, textures, target, groups)
+    }
+
+    /// Layers of each group exported by [`App::export_groups`]. Each group
+    /// keeps its layers as they are in the full composite, so a clipped
+    /// layer at the bottom of a group is still clipped to the layer below
+    /// the group, even though that layer is not drawn.
+    fn linearize_groups(
+        layers: &crate::procreate::SilicaGroup,
+        depth: Option<usize>,
+        include_hidden: bool,
+    ) -> Vec<(Vec<CompositeLayer>, LayerInfo)> {
+        struct State<'a> {
+            depth: Option<usize>,
+            include_hidden: bool,
+            /// Position of the next layer among all layers of the document,
+            /// hidden ones included.
+            index: usize,
+            document: &'a [(CompositeLayer, LayerInfo)],
+            group_path: Vec<String>,
+            groups: Vec<(Vec<CompositeLayer>, LayerInfo)>,
+        }
+
+        fn inner(group: &crate::procreate::SilicaGroup, hidden: bool, state: &mut State<'_>) {
+            for child in group.children.iter().rev() {
+                let SilicaHierarchy::Group(group) = child else {
+                    state.index += 1;
+                    continue;
+                };
+                let layers = state.index..state.index + group.layer_count();
+                let hidden = hidden || group.hidden;
+                let level = state.group_path.len() + 1;
+
+                if (state.include_hidden || !hidden)
+                    && state.depth.is_none_or(|depth| depth == level)
+                {
+                    let info = LayerInfo {
+                        uuid: group.uuid.clone().unwrap_or_default(),
+                        name: group.name.clone(),
+                        group_path: state.group_path.clone(),
+                        blend: BlendingMode::Normal,
+                        opacity: 1.0,
+                        hidden,
+                        clipped: false,
+                        group: true,
+                        // Empty groups sit below the layer above them.
+                        z_order: layers.end.saturating_sub(1).max(layers.start),
+                    };
+                    let layers = state
+                        .document
+                        .iter()
+                        .filter(|(_, info)| layers.contains(&info.z_order))
+                        .map(|(layer, _)| layer.clone())
+                        .collect();
+                    state.groups.push((layers, info));
+                }
+
+                state
+                    .group_path
+                    .push(group.name.clone().unwrap_or_default());
+                inner(group, hidden, state);
+                state.group_path.pop();
             }
         }
 
-        image_buffers
+        let document = App::linearize_silica_layers_with_info(layers, include_hidden);
+        let mut state = State {
+            depth,
+            include_hidden,
+            index: 0,
+            document: &document,
+            group_path: Vec::new(),
+            groups: Vec::new(),
+        };
+        inner(layers, false, &mut state);
+        state.groups
+    }
+
+    /// Composite the layers selected by `filter` into a single image, over
+    /// the document background. Hidden layers are only drawn with
+    /// [`LayerFilter::include_hidden`].
+    pub async fn composite_selected_layers(
+        &self,
+        file: &ProcreateFile,
+        textures: &LayerTextures,
+        target: &mut CompositorTarget,
+        filter: &LayerFilter,
+    ) -> Result<DynamicImage, ProcreateError> {
+        let layers: Vec<_> = App::linearize_selected_layers(&file.layers, filter)
+            .into_iter()
+            .map(|(layer, _)| layer)
+            .collect();
+        let background = (!file.background_hidden).then_some(file.background_color);
+        target.render(&self.pipeline, background, &layers, textures);
+
+        let output = target.output.as_ref().ok_or(ProcreateError::Readback)?;
+        output.texture.export_texture(&target.dev, target.dim).await
     }
 
     /// Transform tree structure of layers into a linear list of
     /// layers for rendering.
     pub fn linearize_silica_layers(layers: &crate::procreate::SilicaGroup) -> Vec<CompositeLayer> {
+        Self::linearize_silica_layers_with_info(layers, false)
+            .into_iter()
+            .map(|(layer, _)| layer)
+            .collect()
+    }
+
+    /// Same as [`App::linearize_silica_layers_with_info`], but only for the
+    /// layers selected by `filter`. Layers keep the z-order they have in
+    /// the whole document.
+    pub fn linearize_selected_layers(
+        layers: &crate::procreate::SilicaGroup,
+        filter: &LayerFilter,
+    ) -> Vec<(CompositeLayer, LayerInfo)> {
+        let mut selected =
+            Self::linearize_silica_layers_with_info(&filter.apply(layers), filter.include_hidden);
+        if !filter.selectors.is_empty() {
+            let z_orders: HashMap<_, _> = Self::linearize_silica_layers_with_info(layers, true)
+                .into_iter()
+                .map(|(_, info)| (info.uuid, info.z_order))
+                .collect();
+            for (_, info) in &mut selected {
+                if let Some(&z_order) = z_orders.get(&info.uuid) {
+                    info.z_order = z_order;
+                }
+            }
+        }
+        selected
+    }
+
+    /// Same as [`App::linearize_silica_layers`], along with where each
+    /// layer comes from in the document. With `include_hidden`, hidden
+    /// layers and groups are kept as if they were visible, but are still
+    /// marked as hidden.
+    pub fn linearize_silica_layers_with_info(
+        layers: &crate::procreate::SilicaGroup,
+        include_hidden: bool,
+    ) -> Vec<(CompositeLayer, LayerInfo)> {
+        struct State<'a> {
+            include_hidden: bool,
+            /// Position of the next layer among all layers of the document,
+            /// hidden ones included.
+            index: usize,
+            composite_layers: Vec<(CompositeLayer, LayerInfo)>,
+            mask_layer: Option<(u32, &'a crate::procreate::SilicaLayer)>,
+            group_path: Vec<String>,
+        }
+
         fn inner<'a>(
             layers: &'a crate::procreate::SilicaGroup,
-            composite_layers: &mut Vec<CompositeLayer>,
-            mask_layer: &mut Option<(u32, &'a crate::procreate::SilicaLayer)>,
+            hidden: bool,
+            state: &mut State<'a>,
         ) {
             for layer in layers.children.iter().rev() {
                 match layer {
-                    SilicaHierarchy::Group(group) if !group.hidden => {
-                        inner(group, composite_layers, mask_layer);
+                    SilicaHierarchy::Group(group) if state.include_hidden || !group.hidden => {
+                        state
+                            .group_path
+                            .push(group.name.clone().unwrap_or_default());
+                        inner(group, hidden || group.hidden, state);
+                        state.group_path.pop();
                     }
-                    SilicaHierarchy::Layer(layer) if !layer.hidden => {
-                        if let Some((_, mask_layer)) = mask_layer {
-                            if layer.clipped && mask_layer.hidden {
-                                continue;
-                            }
-                        }
+                    SilicaHierarchy::Group(group) => state.index += group.layer_count(),
+                    SilicaHierarchy::Layer(layer) => {
+                        let z_order = state.index;
+                        state.index += 1;
 
+                        // Hidden layers still count as the base of the
+                        // clipped layers above them.
                         if !layer.clipped {
-                            *mask_layer = Some((layer.image, layer));
+                            state.mask_layer = Some((layer.image, layer));
+                        }
+                        if !state.include_hidden {
+                            if layer.hidden {
+                                continue;
+                            }
+                            if let Some((_, mask_layer)) = state.mask_layer {
+                                if layer.clipped && mask_layer.hidden {
+                                    continue;
+                                }
+                            }
                         }
 
-                        composite_layers.push(CompositeLayer {
+                        let composite_layer = CompositeLayer {
                             texture: layer.image,
-                            clipped: layer.clipped.then(|| mask_layer.unwrap().0),
+                            // A clipped layer without any layer below it
+                            // is rendered as if it was not clipped.
+                            clipped: state
+                                .mask_layer
+                                .filter(|_| layer.clipped)
+                                .map(|(mask, _)| mask),
                             opacity: layer.opacity,
                             blend: layer.blend,
-                        });
+                        };
+                        let info = LayerInfo {
+                            uuid: layer.uuid.clone(),
+                            name: layer.name.clone(),
+                            group_path: state.group_path.clone(),
+                            blend: layer.blend,
+                            opacity: layer.opacity,
+                            hidden: hidden || layer.hidden,
+                            clipped: layer.clipped,
+                            group: false,
+                            z_order,
+                        };
+                        state.composite_layers.push((composite_layer, info));
                     }
-                    _ => continue,
                 }
             }
         }
 
-        let mut composite_layers = Vec::new();
-        inner(layers, &mut composite_layers, &mut None);
-        composite_layers
+        let mut state = State {
+            include_hidden,
+            index: 0,
+            composite_layers: Vec::new(),
+            mask_layer: None,
+            group_path: Vec::new(),
+        };
+        inner(layers, false, &mut state);
+        state.composite_layers
+    }
+}
+
+/// What each image of a [`LayerExport`] shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportMode {
+    /// Each layer on its own, over the background.
+    #[default]
+    Isolated,
+    /// The artwork after each successive layer is added, starting from
+    /// the bottom layer. Each step is composited over the previous one
+    /// instead of being rendered from scratch.
+    Progressive,
+    /// Each layer blended over the composite of all layers below it, but
+    /// only where the layer itself has coverage. The layers below are the
+    /// ones exported before it, so with a [`LayerFilter`] only selected
+    /// layers are part of the composite, not the whole document.
+    InContext,
+    /// The difference between the composite with and without each layer,
+    /// which shows what each layer changes. As with
+    /// [`ExportMode::InContext`], only layers exported before it count.
+    Difference,
+}
+
+impl ExportMode {
+    /// Number of images read back for each exported layer.
+    fn readbacks(self) -> usize {
+        match self {
+            Self::Isolated | Self::Progressive => 1,
+            Self::InContext | Self::Difference => 2,
+        }
+    }
+
+    /// Combine the images read back for a layer into the exported image.
+    fn combine(self, mut images: Vec<DynamicImage>) -> DynamicImage {
+        match self {
+            Self::Isolated | Self::Progressive => images.swap_remove(0),
+            // Keep the colors of the composite, with the coverage of the layer.
+            Self::InContext => combine_images(&images[0], &images[1], |layer, with, out| {
+                let alpha = layer[3];
+                for c in 0..3 {
+                    out[c] = if with[3] > 0.0 {
+                        with[c] / with[3] * alpha
+                    } else {
+                        0.0
+                    };
+                }
+                out[3] = alpha;
+            }),
+            Self::Difference => combine_images(&images[0], &images[1], |below, with, out| {
+                for c in 0..3 {
+                    out[c] = (with[c] - below[c]).abs();
+                }
+                out[3] = with[3].max(below[3]);
+            }),
+        }
+    }
+}
+
+/// Combine two premultiplied images of the same size pixel by pixel. The
+/// result has the same pixel type as `a`.
+fn combine_images(
+    a: &DynamicImage,
+    b: &DynamicImage,
+    f: impl Fn(&[f32], &[f32], &mut [f32]) + Sync,
+) -> DynamicImage {
+    let (a32, b32) = (a.to_rgba32f(), b.to_rgba32f());
+    let mut out = Rgba32FImage::new(a32.width(), a32.height());
+    out.par_chunks_exact_mut(4)
+        .zip(a32.par_chunks_exact(4).zip(b32.par_chunks_exact(4)))
+        .for_each(|(out, (a, b))| f(a, b, out));
+
+    match a {
+        DynamicImage::ImageRgba8(_) => DynamicImage::ImageRgba8(DynamicImage::from(out).to_rgba8()),
+        _ => DynamicImage::ImageRgba32F(out),
+    }
+}
+
+/// Where a layer of a linearized layer list comes from in the document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerInfo {
+    /// Empty for groups that the document stores without a UUID.
+    pub uuid: String,
+    pub name: Option<String>,
+    /// Names of the groups containing the layer, outermost first.
+    pub group_path: Vec<String>,
+    pub blend: BlendingMode,
+    pub opacity: f32,
+    pub hidden: bool,
+    pub clipped: bool,
+    /// Whether this is a whole group flattened into one image, rather
+    /// than a single layer.
+    pub group: bool,
+    /// Stacking order of the layer among all layers of the document,
+    /// hidden ones included, starting at 0 for the bottom layer. This is
+    /// the position [`crate::procreate::LayerSelector::Index`] selects.
+    /// Groups share the z-order of their topmost layer.
+    pub z_order: usize,
+}
+
+/// Image of a single exported layer.
+#[derive(Debug, Clone)]
+pub struct ExportedImage {
+    /// Index of the image among the exported images, from the bottom. See
+    /// [`LayerInfo::z_order`] for the place of the layer in the document.
+    pub index: usize,
+    pub layer: LayerInfo,
+    pub image: DynamicImage,
+    /// Area of the canvas the image covers. This is the whole canvas
+    /// unless the export is trimmed.
+    pub bounds: Rect,
+}
+
+/// Streaming export of individual layers. See [`App::export_layers`].
+pub struct LayerExport<'a> {
+    app: &'a App,
+    textures: &'a LayerTextures,
+    target: CompositorTarget,
+    background: Option<[f32; 4]>,
+    /// Layers flattened into each exported image.
+    layers: std::iter::Enumerate<std::vec::IntoIter<(Vec<CompositeLayer>, LayerInfo)>>,
+    batch_size: usize,
+    mode: ExportMode,
+    alpha: AlphaMode,
+    trim: bool,
+    staging: StagingBuffers,
+    /// Composite of every layer exported so far, for the modes that
+    /// build on the layers below.
+    composite: Option<GpuTexture>,
+    ready: VecDeque<ExportedImage>,
+}
+
+impl<'a> LayerExport<'a> {
+    fn new(
+        app: &'a App,
+        file: &ProcreateFile,
+        textures: &'a LayerTextures,
+        target: CompositorTarget,
+        layers: Vec<(Vec<CompositeLayer>, LayerInfo)>,
+    ) -> Self {
+        Self {
+            app,
+            textures,
+            target,
+            background: (!file.background_hidden).then_some(file.background_color),
+            layers: layers.into_iter().enumerate(),
+            batch_size: 1,
+            mode: ExportMode::default(),
+            alpha: AlphaMode::default(),
+            trim: false,
+            staging: StagingBuffers::default(),
+            composite: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Render up to `size` layers back to back and copy each of them into
+    /// its own staging buffer, then map those buffers together. This trades
+    /// holding `size` images in memory for far fewer GPU round trips.
+    pub fn batched(mut self, size: usize) -> Self {
+        self.batch_size = size.max(1);
+        self
+    }
+
+    /// What each exported image shows. Defaults to isolated layers.
+    pub fn mode(mut self, mode: ExportMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Alpha mode of the exported images. Defaults to straight alpha.
+    pub fn alpha(mut self, alpha: AlphaMode) -> Self {
+        self.alpha = alpha;
+        self
+    }
+
+    /// Crop each exported image to the bounds of its visible pixels. The
+    /// offset of each image on the canvas is kept in
+    /// [`ExportedImage::bounds`]. Trimmed images are rendered without the
+    /// background, which would otherwise cover the whole canvas.
+    pub fn trimmed(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Number of layers left to export.
+    pub fn len(&self) -> usize {
+        self.layers.len() + self.ready.len()
+    }
+
+    /// Whether all layers have been exported.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stop exporting, and give back the compositor target to render
+    /// something else with it.
+    pub fn into_target(self) -> CompositorTarget {
+        self.target
+    }
+
+    /// Render and read back the next layer.
+    pub async fn next_layer(&mut self) -> Option<Result<ExportedImage, ProcreateError>> {
+        if self.ready.is_empty() {
+            if let Err(err) = self.render_batch().await {
+                return Some(Err(err));
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+
+    /// Background the layers are rendered over.
+    fn background(&self) -> Option<[f32; 4]> {
+        self.background.filter(|_| !self.trim)
+    }
+
+    /// Render and read back the next batch of layers.
+    async fn render_batch(&mut self) -> Result<(), ProcreateError> {
+        let batch = self
+            .layers
+            .by_ref()
+            .take(self.batch_size)
+            .collect::<Vec<_>>();
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let readbacks = self.mode.readbacks();
+        let dim = self.target.dim;
+        let format = self.target.precision.format();
+        self.staging
+            .reserve(&self.target.dev, dim, format, batch.len() * readbacks);
+
+        // Every layer of the batch is rendered and copied in one submission.
+        let mut encoder = self.target.create_encoder();
+        for (slot, (_, (layers, _))) in batch.iter().enumerate() {
+            let slot = slot * readbacks;
+            let encoder = &mut encoder;
+
+            match self.mode {
+                ExportMode::Isolated => {
+                    self.render_to(encoder, self.background(), None, layers, slot)
+                }
+                ExportMode::Progressive => self.render_step(encoder, layers, slot),
+                ExportMode::InContext => {
+                    self.render_to(encoder, None, None, layers, slot);
+                    self.render_step(encoder, layers, slot + 1);
+                }
+                ExportMode::Difference => {
+                    self.copy_composite(encoder, slot);
+                    self.render_step(encoder, layers, slot + 1);
+                }
+            }
+        }
+        self.target.submit(encoder);
+
+        let images = self
+            .staging
+            .read(&self.target.dev, batch.len() * readbacks)
+            .await?;
+
+        let mut images = images.into_iter();
+        for (index, (_, layer)) in batch {
+            let mut image = self.mode.combine(images.by_ref().take(readbacks).collect());
+            self.alpha.apply(&mut image);
+            let (image, bounds) = if self.trim {
+                export::trim(&image)
+            } else {
+                let bounds = Rect::of_image(&image);
+                (image, bounds)
+            };
+            self.ready.push_back(ExportedImage {
+                index,
+                layer,
+                image,
+                bounds,
+            });
+        }
+        Ok(())
+    }
+
+    /// Record the rendering of layers over the background or over `base`,
+    /// and a copy of the output into the given staging buffer slot.
+    fn render_to(
+        &mut self,
+        encoder: &mut CommandEncoder,
+        bg: Option<[f32; 4]>,
+        base: Option<&GpuTexture>,
+        layers: &[CompositeLayer],
+        slot: usize,
+    ) {
+        self.target
+            .record(encoder, &self.app.pipeline, bg, base, layers, self.textures);
+        if let Some(output) = self.target.output.as_ref() {
+            let buffer = self.staging.buffer(slot);
+            output
+                .texture
+                .copy_to_buffer(encoder, buffer, self.target.dim);
+        }
+    }
+
+    /// Record the rendering of layers over the running composite and a
+    /// copy of the output into the given staging buffer slot, then make
+    /// the output the new running composite.
+    fn render_step(
+        &mut self,
+        encoder: &mut CommandEncoder,
+        layers: &[CompositeLayer],
+        slot: usize,
+    ) {
+        let base = self.composite.take();
+        self.render_to(encoder, self.background(), base.as_ref(), layers, slot);
+
+        let Some(output) = self.target.output.as_ref() else {
+            return;
+        };
+        let composite = base.unwrap_or_else(|| {
+            GpuTexture::empty_with_format(
+                &self.target.dev,
+                output.texture.size,
+                GpuTexture::OUTPUT_USAGE | wgpu::TextureUsages::COPY_DST,
+                output.texture.format,
+            )
+        });
+        output.texture.copy_to_texture(encoder, &composite);
+        self.composite = Some(composite);
+    }
+
+    /// Record a copy of the running composite into the given staging buffer
+    /// slot. Before any layer is exported, the composite is only the
+    /// background.
+    fn copy_composite(&mut self, encoder: &mut CommandEncoder, slot: usize) {
+        match self.composite.as_ref() {
+            Some(composite) => {
+                composite.copy_to_buffer(encoder, self.staging.buffer(slot), self.target.dim)
+            }
+            None => self.render_to(encoder, self.background(), None, &[], slot),
+        }
     }
 }
 
+/// Images and layers for tests of the exporters.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    /// Exported image of a visible, opaque layer, one transparent pixel
+    /// in size.
+    pub fn exported(index: usize, name: Option<&str>, group_path: &[&str]) -> ExportedImage {
+        let image = DynamicImage::new_rgba8(1, 1);
+        ExportedImage {
+            index,
+            layer: LayerInfo {
+                uuid: format!("{index:08X}-0000-4000-8000-000000000000"),
+                name: name.map(String::from),
+                group_path: group_path.iter().map(|group| group.to_string()).collect(),
+                blend: BlendingMode::Normal,
+                opacity: 1.0,
+                hidden: false,
+                clipped: false,
+                group: false,
+                z_order: index,
+            },
+            bounds: Rect::of_image(&image),
+            image,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procreate::fixtures::layer;
+    use crate::procreate::{LayerSelector, SilicaGroup};
+
+    fn document() -> SilicaGroup {
+        SilicaGroup {
+            hidden: false,
+            name: None,
+            uuid: None,
+            children: vec![
+                layer("Ink", false),
+                SilicaHierarchy::Group(SilicaGroup {
+                    hidden: true,
+                    name: Some(String::from("Sketches")),
+                    uuid: Some(String::from("SKETCHES")),
+                    children: vec![layer("Sketch 2", false), layer("Sketch 1", false)],
+                }),
+                layer("Paper", false),
+            ],
+        }
+    }
+
+    #[test]
+    fn document_z_order() {
+        let z_orders = |layers: Vec<(CompositeLayer, LayerInfo)>| {
+            layers
+                .into_iter()
+                .map(|(_, info)| (info.name.unwrap(), info.z_order))
+                .collect::<Vec<_>>()
+        };
+
+        let visible = App::linearize_silica_layers_with_info(&document(), false);
+        let expected = [("Paper", 0), ("Ink", 3)].map(|(name, z)| (name.to_string(), z));
+        assert_eq!(z_orders(visible), expected);
+
+        let filter = LayerFilter::new().with(LayerSelector::name_glob("Ink").unwrap());
+        let selected = App::linearize_selected_layers(&document(), &filter);
+        assert_eq!(z_orders(selected), [(String::from("Ink"), 3)]);
+    }
+
+    #[test]
+    fn selected_hidden_layers() {
+        let filter = LayerFilter::new().with(LayerSelector::Index(1..2));
+        assert!(App::linearize_selected_layers(&document(), &filter).is_empty());
+
+        let selected = App::linearize_selected_layers(&document(), &filter.with_hidden(true));
+        let [(_, info)] = &selected[..] else {
+            panic!("expected one layer");
+        };
+        assert_eq!(info.name.as_deref(), Some("Sketch 1"));
+        assert!(info.hidden);
+    }
+
+    fn pixel(rgba: [f32; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(1, 1, image::Rgba(rgba)))
+    }
+
+    fn combined(mode: ExportMode, a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+        mode.combine(vec![pixel(a), pixel(b)])
+            .to_rgba32f()
+            .get_pixel(0, 0)
+            .0
+    }
+
+    #[test]
+    fn combine_in_context() {
+        let composite = [0.2, 0.4, 0.6, 1.0];
+        // Where the layer is transparent, nothing of the composite is kept.
+        assert_eq!(
+            combined(ExportMode::InContext, [0.0; 4], composite),
+            [0.0; 4]
+        );
+        assert_eq!(
+            combined(ExportMode::InContext, [0.5, 0.5, 0.5, 0.5], composite),
+            [0.1, 0.2, 0.3, 0.5]
+        );
+        assert_eq!(
+            combined(ExportMode::InContext, [1.0; 4], [0.0; 4]),
+            [0.0, 0.0, 0.0, 1.0]
+        );
+
+        // 8-bit images stay 8-bit.
+        let layer = DynamicImage::ImageRgba8(image::RgbaImage::new(1, 1));
+        let combined = ExportMode::InContext.combine(vec![layer.clone(), layer]);
+        assert!(matches!(combined, DynamicImage::ImageRgba8(_)));
+    }
+
+    #[test]
+    fn combine_difference() {
+        let below = [0.2, 0.4, 0.6, 1.0];
+        assert_eq!(
+            combined(ExportMode::Difference, below, below),
+            [0.0, 0.0, 0.0, 1.0]
+        );
+        assert_eq!(
+            combined(ExportMode::Difference, [0.0; 4], [0.5, 0.25, 0.0, 0.5]),
+            [0.5, 0.25, 0.0, 0.5]
+        );
+    }
+
+    #[test]
+    fn hidden_groups() {
+        assert!(App::linearize_groups(&document(), None, false).is_empty());
+
+        let groups = App::linearize_groups(&document(), None, true);
+        let [(layers, info)] = &groups[..] else {
+            panic!("expected one group");
+        };
+        assert_eq!(layers.len(), 2);
+        assert_eq!(info.uuid, "SKETCHES");
+        assert!(info.hidden && info.group);
+        // The group stacks where its top layer, "Sketch 2", does.
+        assert_eq!(info.z_order, 2);
+    }
+
+    #[test]
+    fn group_clipped_to_layer_below() {
+        let document = SilicaGroup {
+            hidden: false,
+            name: None,
+            uuid: None,
+            children: vec![
+                SilicaHierarchy::Group(SilicaGroup {
+                    hidden: false,
+                    name: Some(String::from("Shading")),
+                    uuid: None,
+                    children: vec![layer("Highlights", false), layer("Shadows", true)],
+                }),
+                layer("Color", false),
+            ],
+        };
+        let [(layers, info)] = &App::linearize_groups(&document, None, false)[..] else {
+            panic!("expected one group");
+        };
+        assert_eq!(info.z_order, 2);
+        // "Shadows" is drawn clipped to "Color", as in the full composite.
+        let clipped: Vec<_> = layers.iter().map(|layer| layer.clipped.is_some()).collect();
+        assert_eq!(clipped, [true, false]);
+    }
+}
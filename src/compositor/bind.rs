@@ -71,11 +71,14 @@ impl CpuBuffers {
 /// Shader buffers on the GPU side.
 pub(super) struct GpuBuffers {
     dev: Arc<GpuHandle>,
-    size: usize,
+    /// Number of layers the buffers have room for.
+    pub(super) size: usize,
     pub(super) blends: wgpu::Buffer,
     pub(super) opacities: wgpu::Buffer,
     pub(super) masks: wgpu::Buffer,
     pub(super) layers: wgpu::Buffer,
+    /// Layer count uniform, used when push constants are unavailable.
+    pub(super) count: wgpu::Buffer,
 }
 
 impl GpuBuffers {
@@ -92,6 +95,13 @@ impl GpuBuffers {
             opacities: dev.device.create_buffer(&storage_desc),
             masks: dev.device.create_buffer(&storage_desc),
             layers: dev.device.create_buffer(&storage_desc),
+            count: dev.device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                // Padded to the minimum uniform buffer binding size
+                size: 16,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
             dev,
             size,
         }
@@ -107,5 +117,8 @@ impl GpuBuffers {
         q.write_buffer(&self.opacities, 0, bytemuck::cast_slice(&cpu.opacities));
         q.write_buffer(&self.masks, 0, bytemuck::cast_slice(&cpu.masks));
         q.write_buffer(&self.layers, 0, bytemuck::cast_slice(&cpu.layers));
+        if !self.dev.push_constants {
+            q.write_buffer(&self.count, 0, &cpu.count.to_ne_bytes());
+        }
     }
 }
@@ -1,3 +1,5 @@
+use crate::procreate::ProcreateError;
+
 /// Represents a grouping of useful GPU resources.
 #[derive(Debug)]
 pub struct GpuHandle {
@@ -9,12 +11,59 @@ pub struct GpuHandle {
     pub device: wgpu::Device,
     /// Device command queue.
     pub queue: wgpu::Queue,
+    /// Whether the device was created with push constants. When it was
+    /// not, the compositor passes the layer count through a uniform buffer.
+    pub push_constants: bool,
+}
+
+/// Selects a specific adapter among the ones available for the
+/// configured backends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterSelector {
+    /// First adapter whose name contains this string, ignoring case.
+    Name(String),
+    /// Adapter at this position in [`GpuHandle::list_adapters`].
+    Index(usize),
+}
+
+impl AdapterSelector {
+    fn matches(&self, index: usize, info: &wgpu::AdapterInfo) -> bool {
+        match self {
+            Self::Name(name) => info.name.to_lowercase().contains(&name.to_lowercase()),
+            Self::Index(i) => *i == index,
+        }
+    }
+}
+
+/// Options used to pick the adapter of a [`GpuHandle`].
+#[derive(Debug, Clone)]
+pub struct GpuOptions {
+    /// Backends to enumerate adapters from.
+    pub backends: wgpu::Backends,
+    /// Power preference when no specific adapter is selected.
+    pub power_preference: wgpu::PowerPreference,
+    /// Force the fallback (software) adapter when no specific adapter
+    /// is selected.
+    pub force_fallback_adapter: bool,
+    /// Select a specific adapter instead of letting WGPU pick one.
+    pub adapter: Option<AdapterSelector>,
+}
+
+impl Default for GpuOptions {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::PRIMARY,
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            adapter: None,
+        }
+    }
 }
 
 impl GpuHandle {
-    pub fn instance_descriptor() -> wgpu::InstanceDescriptor {
+    pub fn instance_descriptor(backends: wgpu::Backends) -> wgpu::InstanceDescriptor {
         wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::PRIMARY,
+            backends,
             dx12_shader_compiler: wgpu::Dx12Compiler::Dxc {
                 dxil_path: None,
                 dxc_path: None,
@@ -24,46 +73,88 @@ impl GpuHandle {
         }
     }
 
-    const ADAPTER_OPTIONS: wgpu::RequestAdapterOptions<'static> = wgpu::RequestAdapterOptions {
-        power_preference: wgpu::PowerPreference::HighPerformance,
-        compatible_surface: None,
-        force_fallback_adapter: false,
-    };
-
     /// Create a bare GPU handle with no surface target.
-    pub async fn new() -> Option<Self> {
-        let instance = wgpu::Instance::new(Self::instance_descriptor());
-        let adapter = instance.request_adapter(&Self::ADAPTER_OPTIONS).await?;
+    pub async fn new() -> Result<Self, ProcreateError> {
+        Self::with_options(&GpuOptions::default()).await
+    }
+
+    /// Create a bare GPU handle with no surface target, picking the
+    /// adapter according to the given options.
+    pub async fn with_options(options: &GpuOptions) -> Result<Self, ProcreateError> {
+        let instance = wgpu::Instance::new(Self::instance_descriptor(options.backends));
+        let adapter = match &options.adapter {
+            Some(selector) => instance
+                .enumerate_adapters(options.backends)
+                .enumerate()
+                .find(|(index, adapter)| selector.matches(*index, &adapter.get_info()))
+                .map(|(_, adapter)| adapter),
+            None => {
+                instance
+                    .request_adapter(&wgpu::RequestAdapterOptions {
+                        power_preference: options.power_preference,
+                        compatible_surface: None,
+                        force_fallback_adapter: options.force_fallback_adapter,
+                    })
+                    .await
+            }
+        };
+
+        let Some(adapter) = adapter else {
+            log::warn!("No GPU adapter found for {options:?}");
+            return Err(ProcreateError::NoAdapter);
+        };
         Self::from_adapter(instance, adapter).await
     }
 
+    /// List the adapters available for the given backends, in the order
+    /// used by [`AdapterSelector::Index`].
+    pub fn list_adapters(backends: wgpu::Backends) -> Vec<wgpu::AdapterInfo> {
+        wgpu::Instance::new(Self::instance_descriptor(backends))
+            .enumerate_adapters(backends)
+            .map(|adapter| adapter.get_info())
+            .collect()
+    }
+
     /// Request device.
-    async fn from_adapter(instance: wgpu::Instance, adapter: wgpu::Adapter) -> Option<Self> {
+    async fn from_adapter(
+        instance: wgpu::Instance,
+        adapter: wgpu::Adapter,
+    ) -> Result<Self, ProcreateError> {
         // Debugging information
-        dbg!(adapter.get_info());
-        dbg!(adapter.limits());
+        log::info!("Using adapter {:?}", adapter.get_info());
+        log::debug!("Adapter limits {:?}", adapter.limits());
+
+        // Push constants are only an optimisation, since WebGPU and many
+        // GLES or software adapters do not support them.
+        let push_constants = adapter.features().contains(wgpu::Features::PUSH_CONSTANTS);
+        let adapter_limits = adapter.limits();
 
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::PUSH_CONSTANTS,
+                    features: if push_constants {
+                        wgpu::Features::PUSH_CONSTANTS
+                    } else {
+                        wgpu::Features::empty()
+                    },
                     limits: wgpu::Limits {
-                        max_push_constant_size: 4,
-                        max_buffer_size: 1024 << 20,
-                        ..Default::default()
+                        max_push_constant_size: if push_constants { 4 } else { 0 },
+                        max_buffer_size: adapter_limits.max_buffer_size.min(1024 << 20),
+                        max_texture_array_layers: adapter_limits.max_texture_array_layers,
+                        ..wgpu::Limits::downlevel_defaults().using_resolution(adapter_limits)
                     },
                     ..Default::default()
                 },
                 None,
             )
-            .await
-            .ok()?;
+            .await?;
 
-        Some(Self {
+        Ok(Self {
             instance,
             device,
             adapter,
             queue,
+            push_constants,
         })
     }
 }
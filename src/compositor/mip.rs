@@ -0,0 +1,199 @@
+use super::dev::GpuHandle;
+use super::tex::{GpuTexture, LayerTextures, TEX_FORMAT};
+
+/// Render pipeline that halves layer textures, one mip level at a time.
+///
+/// Sampling a texture with a linear filter only reads the 2x2 texels
+/// closest to each output pixel, so reducing layers by much more than half
+/// in a single pass skips most of their pixels. Halving them first until
+/// less than a 2x reduction remains keeps every pixel in the output.
+pub struct MipPipeline {
+    sampler: wgpu::Sampler,
+    bind_group_layout: wgpu::BindGroupLayout,
+    render_pipeline: wgpu::RenderPipeline,
+}
+
+impl MipPipeline {
+    pub fn new(dev: &GpuHandle) -> Self {
+        let device = &dev.device;
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("mip_sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("mip_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("../mip.wgsl"));
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("mip_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("mip_pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: TEX_FORMAT,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            sampler,
+            bind_group_layout,
+            render_pipeline,
+        }
+    }
+
+    /// Number of times layers should be halved before rendering them at
+    /// `scale` times their size, so that less than a 2x reduction is left
+    /// to the compositor's sampler.
+    pub fn levels_for_scale(scale: f32) -> u32 {
+        if scale >= 0.5 || scale <= 0.0 {
+            0
+        } else {
+            (1.0 / scale).log2().floor() as u32
+        }
+    }
+
+    /// Copy of the layer textures halved `levels` times, or `None` when
+    /// there is nothing to halve.
+    pub fn downsample(
+        &self,
+        dev: &GpuHandle,
+        textures: &LayerTextures,
+        levels: u32,
+    ) -> Option<LayerTextures> {
+        let mut current: Option<LayerTextures> = None;
+        for _ in 0..levels {
+            let source = current.as_ref().unwrap_or(textures);
+            let size = source.arrays[0].size;
+            if size.width == 1 && size.height == 1 {
+                break;
+            }
+            current = Some(self.halve(dev, source));
+        }
+        current
+    }
+
+    /// Halve every layer of the layer textures.
+    fn halve(&self, dev: &GpuHandle, source: &LayerTextures) -> LayerTextures {
+        let size = source.arrays[0].size;
+        let layers = source.arrays.iter().map(GpuTexture::layers).sum();
+        let halved = LayerTextures::new(
+            dev,
+            (size.width / 2).max(1),
+            (size.height / 2).max(1),
+            layers,
+            GpuTexture::LAYER_USAGE | wgpu::TextureUsages::RENDER_ATTACHMENT,
+        );
+
+        let mut encoder = dev
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        for (src, dst) in source.arrays.iter().zip(&halved.arrays) {
+            for layer in 0..src.layers() {
+                let src_view = layer_view(src, layer);
+                let dst_view = layer_view(dst, layer);
+
+                let bind_group = dev.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("mip_bind_group"),
+                    layout: &self.bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::Sampler(&self.sampler),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::TextureView(&src_view),
+                        },
+                    ],
+                });
+
+                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("mip_pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: &dst_view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                });
+                pass.set_pipeline(&self.render_pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.draw(0..3, 0..1);
+            }
+        }
+        dev.queue.submit(Some(encoder.finish()));
+
+        halved
+    }
+}
+
+/// View of a single layer of a texture array.
+fn layer_view(texture: &GpuTexture, layer: u32) -> wgpu::TextureView {
+    texture.texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_array_layer: layer,
+        array_layer_count: Some(1),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_leave_less_than_half() {
+        assert_eq!(MipPipeline::levels_for_scale(1.0), 0);
+        assert_eq!(MipPipeline::levels_for_scale(0.5), 0);
+        assert_eq!(MipPipeline::levels_for_scale(0.4), 1);
+        assert_eq!(MipPipeline::levels_for_scale(0.25), 2);
+        assert_eq!(MipPipeline::levels_for_scale(0.1), 3);
+        assert_eq!(MipPipeline::levels_for_scale(2.0), 0);
+    }
+}
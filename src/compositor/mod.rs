@@ -1,11 +1,12 @@
 mod bind;
 pub mod dev;
+pub mod mip;
 pub mod tex;
 
 use self::{
     bind::{CpuBuffers, GpuBuffers},
     dev::GpuHandle,
-    tex::GpuTexture,
+    tex::{GpuTexture, LayerTextures},
 };
 use crate::procreate::BlendingMode;
 use image::{Pixel, Rgba};
@@ -35,6 +36,15 @@ impl BufferDimensions {
 
     /// Computes the buffer dimensions from the GPU texture extent.
     pub const fn from_extent(extent: wgpu::Extent3d) -> Self {
+        Self::from_extent_with_pixel_size(
+            extent,
+            (Rgba::<u8>::CHANNEL_COUNT as usize * std::mem::size_of::<u8>()) as u32,
+        )
+    }
+
+    /// Computes the buffer dimensions from the GPU texture extent and
+    /// the size of its pixels in bytes.
+    pub const fn from_extent_with_pixel_size(extent: wgpu::Extent3d, bytes_per_pixel: u32) -> Self {
         // It is a WebGPU requirement that
         // ImageCopyBuffer.layout.bytes_per_row % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT == 0
         // So we calculate padded_bytes_per_row by rounding unpadded_bytes_per_row
@@ -43,8 +53,6 @@ impl BufferDimensions {
         debug_assert!(extent.depth_or_array_layers == 1);
         let width = extent.width;
         let height = extent.height;
-        let bytes_per_pixel =
-            (Rgba::<u8>::CHANNEL_COUNT as usize * std::mem::size_of::<u8>()) as u32;
         let unpadded_bytes_per_row = width * bytes_per_pixel;
         let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
         let padded_bytes_per_row_padding = (align - unpadded_bytes_per_row % align) % align;
@@ -61,6 +69,68 @@ impl BufferDimensions {
     fn is_empty(&self) -> bool {
         self.width == 0 || self.height == 0
     }
+
+    /// Strip the row padding from data read back from the GPU, so that
+    /// it holds exactly `width * height` pixels.
+    pub fn unpad(&self, data: &[u8]) -> Vec<u8> {
+        if self.padded_bytes_per_row == self.unpadded_bytes_per_row {
+            return data.to_vec();
+        }
+
+        data.chunks(self.padded_bytes_per_row as usize)
+            .take(self.height as usize)
+            .flat_map(|row| &row[..self.unpadded_bytes_per_row as usize])
+            .copied()
+            .collect()
+    }
+}
+
+/// Precision of the compositor output and intermediate textures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    /// 8-bit normalized channels, the same as the layer textures.
+    #[default]
+    Unorm8,
+    /// 16-bit float channels, which avoids banding when many layers are
+    /// stacked with modes like Multiply or Soft Light.
+    Float16,
+}
+
+impl Precision {
+    /// Texture format of the compositor output.
+    pub const fn format(self) -> wgpu::TextureFormat {
+        match self {
+            Self::Unorm8 => tex::TEX_FORMAT,
+            Self::Float16 => wgpu::TextureFormat::Rgba16Float,
+        }
+    }
+
+    /// Size of an output pixel in bytes.
+    pub const fn bytes_per_pixel(self) -> u32 {
+        match self {
+            Self::Unorm8 => 4,
+            Self::Float16 => 8,
+        }
+    }
+}
+
+/// Color space in which layers are blended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlendColorSpace {
+    /// Blend gamma-encoded sRGB values, like Procreate does.
+    #[default]
+    Gamma,
+    /// Blend in linear light, to match other linear compositing pipelines.
+    Linear,
+}
+
+/// Options of a [`CompositorPipeline`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PipelineOptions {
+    /// Precision of the textures rendered to.
+    pub precision: Precision,
+    /// Color space in which layers are blended.
+    pub blend_space: BlendColorSpace,
 }
 
 /// Vertex input to the shader.
@@ -110,9 +180,9 @@ impl VertexInput {
 /// Compositing layer information.
 #[derive(Debug, Clone)]
 pub struct CompositeLayer {
-    /// Texture index into a `&[GpuBuffer]`.
+    /// Layer index into a [`LayerTextures`].
     pub texture: u32,
-    /// Clipping texture index into a `&[GpuBuffer]`.
+    /// Clipping layer index into a [`LayerTextures`].
     pub clipped: Option<u32>,
     /// Opacity (0.0..=1.0) of the layer.
     pub opacity: f32,
@@ -120,6 +190,59 @@ pub struct CompositeLayer {
     pub blend: BlendingMode,
 }
 
+/// A run of consecutive composite layers that can be drawn in a single
+/// render pass, because their textures all live in one texture array
+/// and their clipping masks all live in one texture array.
+struct CompositePass {
+    /// Texture array index of the layers.
+    textures: usize,
+    /// Texture array index of the clipping masks.
+    masks: usize,
+    /// Layers with texture indices local to their texture arrays.
+    layers: Vec<CompositeLayer>,
+}
+
+impl CompositePass {
+    /// Split composite layers into passes. There is always at least one
+    /// pass, so that the background is still drawn with no layers.
+    fn split(layers: &[CompositeLayer], textures: &LayerTextures) -> Vec<Self> {
+        let mut passes: Vec<Self> = Vec::new();
+
+        for layer in layers {
+            let (array, texture) = textures.locate(layer.texture);
+            let mask = layer.clipped.map(|mask| textures.locate(mask));
+            let local = CompositeLayer {
+                texture,
+                clipped: mask.map(|(_, mask)| mask),
+                ..layer.clone()
+            };
+
+            match passes.last_mut() {
+                Some(pass)
+                    if pass.textures == array
+                        && mask.is_none_or(|(masks, _)| pass.masks == masks) =>
+                {
+                    pass.layers.push(local);
+                }
+                _ => passes.push(Self {
+                    textures: array,
+                    masks: mask.map_or(array, |(masks, _)| masks),
+                    layers: vec![local],
+                }),
+            }
+        }
+
+        if passes.is_empty() {
+            passes.push(Self {
+                textures: 0,
+                masks: 0,
+                layers: Vec::new(),
+            });
+        }
+        passes
+    }
+}
+
 pub struct CompositorData {
     dev: Arc<GpuHandle>,
     vertices: [VertexInput; 4],
@@ -231,6 +354,8 @@ pub struct CompositorTarget {
     pub data: CompositorData,
     /// Output texture dimensions.
     pub dim: BufferDimensions,
+    /// Output texture precision.
+    pub precision: Precision,
     /// Compositor output buffers and texture.
     pub output: Option<CompositorOutput>,
 }
@@ -239,32 +364,50 @@ pub struct CompositorTarget {
 /// can reuse buffers and textures whenever possible.
 pub struct CompositorOutput {
     dev: Arc<GpuHandle>,
-    size: usize,
-    bindings: CpuBuffers,
-    buffers: GpuBuffers,
+    /// Layer bindings of every pass recorded since the last submission.
+    /// Buffer writes only land once commands are submitted, so each pass
+    /// recorded into the same submission needs buffers of its own.
+    buffers: Vec<(CpuBuffers, GpuBuffers)>,
+    recorded: usize,
+    /// Copy of the output, which passes after the first one composite over.
+    previous: Option<GpuTexture>,
     pub texture: GpuTexture,
 }
 
 impl CompositorOutput {
     /// Create a new compositor stage.
-    pub fn new(target: &CompositorTarget, size: usize) -> Self {
+    pub fn new(target: &CompositorTarget) -> Self {
         Self {
             dev: target.dev.clone(),
-            size,
-            bindings: CpuBuffers::new(size),
-            buffers: GpuBuffers::new(target.dev.clone(), size),
+            buffers: Vec::new(),
+            recorded: 0,
+            previous: None,
             texture: target.create_texture(),
         }
     }
 
-    fn reserve_buffers(&mut self, size: usize) {
-        if size <= self.size {
-            return;
-        }
+    /// Layer bindings for the next pass recorded into the current
+    /// submission, with room for at least `size` layers.
+    fn next_buffers(&mut self, size: usize) -> &mut (CpuBuffers, GpuBuffers) {
+        let index = self.recorded;
+        self.recorded += 1;
 
-        self.size = size;
-        self.bindings = CpuBuffers::new(size);
-        self.buffers = GpuBuffers::new(self.dev.clone(), size);
+        let fits = self
+            .buffers
+            .get(index)
+            .is_some_and(|(_, buffers)| buffers.size >= size);
+        if !fits {
+            let buffers = (
+                CpuBuffers::new(size),
+                GpuBuffers::new(self.dev.clone(), size),
+            );
+            if index < self.buffers.len() {
+                self.buffers[index] = buffers;
+            } else {
+                self.buffers.push(buffers);
+            }
+        }
+        &mut self.buffers[index]
     }
 }
 
@@ -275,18 +418,46 @@ impl CompositorTarget {
             data: CompositorData::new(dev.clone()),
             dev,
             dim: BufferDimensions::new(0, 0),
+            precision: Precision::default(),
             output: None,
         }
     }
 
     /// Create an empty texture for this compositor target.
     fn create_texture(&self) -> GpuTexture {
-        GpuTexture::empty_with_extent(&self.dev, self.dim.extent, GpuTexture::OUTPUT_USAGE)
+        GpuTexture::empty_with_format(
+            &self.dev,
+            self.dim.extent,
+            GpuTexture::OUTPUT_USAGE,
+            self.precision.format(),
+        )
+    }
+
+    /// Set the precision of the compositor target's output. It must match
+    /// the precision of the pipeline used to render.
+    pub fn set_precision(&mut self, precision: Precision) -> bool {
+        if self.precision == precision {
+            return false;
+        }
+        self.precision = precision;
+        self.dim = BufferDimensions::from_extent_with_pixel_size(
+            self.dim.extent,
+            precision.bytes_per_pixel(),
+        );
+        self.output = None;
+        true
     }
 
     /// Set the dimensions of the compositor target's output.
     pub fn set_dimensions(&mut self, width: u32, height: u32) -> bool {
-        let buffer_dimensions = BufferDimensions::new(width, height);
+        let buffer_dimensions = BufferDimensions::from_extent_with_pixel_size(
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            self.precision.bytes_per_pixel(),
+        );
         if self.dim == buffer_dimensions {
             return false;
         }
@@ -295,85 +466,243 @@ impl CompositorTarget {
         true
     }
 
+    /// Scale the dimensions of the compositor target's output, keeping
+    /// at least one pixel on each side. Layers are resampled to fit, so
+    /// reductions beyond half size should render from
+    /// [`LayerTextures`] downsampled with a [`mip::MipPipeline`].
+    pub fn scale_dimensions(&mut self, scale: f32) -> bool {
+        let scaled = |size: u32| ((size as f32 * scale).round() as u32).max(1);
+        self.set_dimensions(scaled(self.dim.width), scaled(self.dim.height))
+    }
+
     /// Render composite layers using the compositor pipeline.
+    ///
+    /// Layers are drawn in as many passes as needed for each pass to
+    /// only sample from one texture array for its layers and one for
+    /// its clipping masks. Every pass after the first one composites over
+    /// the output of the previous pass.
     pub fn render(
         &mut self,
         pipeline: &CompositorPipeline,
         bg: Option<[f32; 4]>,
         layers: &[CompositeLayer],
-        textures: &GpuTexture,
+        textures: &LayerTextures,
+    ) {
+        self.render_then(pipeline, bg, layers, textures, |_, _| {});
+    }
+
+    /// Render composite layers using the compositor pipeline, then record
+    /// more commands on the output texture in the same submission as the
+    /// last render pass.
+    pub fn render_then(
+        &mut self,
+        pipeline: &CompositorPipeline,
+        bg: Option<[f32; 4]>,
+        layers: &[CompositeLayer],
+        textures: &LayerTextures,
+        then: impl FnOnce(&mut CommandEncoder, &GpuTexture),
+    ) {
+        self.render_passes(pipeline, bg, None, layers, textures, then);
+    }
+
+    /// Render composite layers over an existing composite instead of the
+    /// background, then record more commands on the output texture like
+    /// [`CompositorTarget::render_then`].
+    ///
+    /// ### Note
+    /// `base` must have the same dimensions and precision as this target,
+    /// and must not be the target's own output texture.
+    pub fn render_over_then(
+        &mut self,
+        pipeline: &CompositorPipeline,
+        base: &GpuTexture,
+        layers: &[CompositeLayer],
+        textures: &LayerTextures,
+        then: impl FnOnce(&mut CommandEncoder, &GpuTexture),
+    ) {
+        self.render_passes(pipeline, None, Some(base), layers, textures, then);
+    }
+
+    fn render_passes(
+        &mut self,
+        pipeline: &CompositorPipeline,
+        bg: Option<[f32; 4]>,
+        base: Option<&GpuTexture>,
+        layers: &[CompositeLayer],
+        textures: &LayerTextures,
+        then: impl FnOnce(&mut CommandEncoder, &GpuTexture),
+    ) {
+        let mut encoder = self.create_encoder();
+        self.record(&mut encoder, pipeline, bg, base, layers, textures);
+        if let Some(output) = self.output.as_ref() {
+            then(&mut encoder, &output.texture);
+        }
+        self.submit(encoder);
+    }
+
+    /// Create a command encoder to record renders into with
+    /// [`CompositorTarget::record`].
+    pub fn create_encoder(&self) -> CommandEncoder {
+        self.dev
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default())
+    }
+
+    /// Record the rendering of composite layers over `base`, or over the
+    /// background if there is no base, without submitting anything.
+    ///
+    /// Renders recorded into the same encoder run in order, so the output
+    /// can be copied elsewhere after each of them. The encoder then has to
+    /// be submitted with [`CompositorTarget::submit`] before recording
+    /// into another one.
+    ///
+    /// ### Note
+    /// `base` must have the same dimensions and precision as this target,
+    /// and must not be the target's own output texture.
+    pub fn record(
+        &mut self,
+        encoder: &mut CommandEncoder,
+        pipeline: &CompositorPipeline,
+        bg: Option<[f32; 4]>,
+        base: Option<&GpuTexture>,
+        layers: &[CompositeLayer],
+        textures: &LayerTextures,
     ) {
         assert!(!self.dim.is_empty(), "set_dimensions required");
+        assert_eq!(
+            self.precision, pipeline.precision,
+            "pipeline and target precision must match"
+        );
 
-        let command_buffers = {
-            let mut encoder = self
-                .dev
-                .device
-                .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        let passes = CompositePass::split(layers, textures);
+        for (index, pass) in passes.iter().enumerate() {
+            let previous = if index == 0 {
+                None
+            } else {
+                self.copy_output(encoder)
+            };
+            let composite = if index == 0 { base } else { previous.as_ref() };
 
-            self.render_command(pipeline, &mut encoder, bg, layers, textures);
+            self.render_command(
+                pipeline,
+                encoder,
+                if index == 0 && base.is_none() {
+                    bg
+                } else {
+                    None
+                },
+                composite,
+                &pass.layers,
+                &textures.arrays[pass.textures],
+                &textures.arrays[pass.masks],
+            );
 
-            encoder.finish()
-        };
-        self.dev.queue.submit(Some(command_buffers));
+            if let (Some(previous), Some(output)) = (previous, self.output.as_mut()) {
+                output.previous = Some(previous);
+            }
+        }
+    }
+
+    /// Submit the renders recorded into an encoder.
+    pub fn submit(&mut self, encoder: CommandEncoder) {
+        self.dev.queue.submit(Some(encoder.finish()));
+        if let Some(output) = self.output.as_mut() {
+            output.recorded = 0;
+        }
     }
 
+    /// Record a copy of the output into a texture of its own, for the next
+    /// pass to composite over.
+    fn copy_output(&mut self, encoder: &mut CommandEncoder) -> Option<GpuTexture> {
+        let output = self.output.as_mut()?;
+        let previous = output.previous.take().unwrap_or_else(|| {
+            GpuTexture::empty_with_format(
+                &self.dev,
+                self.dim.extent,
+                GpuTexture::OUTPUT_USAGE | wgpu::TextureUsages::COPY_DST,
+                self.precision.format(),
+            )
+        });
+        output.texture.copy_to_texture(encoder, &previous);
+        Some(previous)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn render_command(
         &mut self,
         pipeline: &CompositorPipeline,
         encoder: &mut CommandEncoder,
         bg: Option<[f32; 4]>,
+        composite: Option<&GpuTexture>,
         composite_layers: &[CompositeLayer],
         textures: &GpuTexture,
+        masks: &GpuTexture,
     ) {
-        let composite_view = self.create_texture().create_view();
+        let composite_view = match composite {
+            Some(composite) => composite.create_view(),
+            None => self.create_texture().create_view(),
+        };
 
-        let stage = if let Some(stage) = self.output.as_mut() {
-            stage.reserve_buffers(composite_layers.len());
-            stage
-        } else {
-            self.output
-                .insert(CompositorOutput::new(self, composite_layers.len()))
+        let stage = match self.output.as_mut() {
+            Some(stage) => stage,
+            None => self.output.insert(CompositorOutput::new(self)),
         };
+        let output_view = stage.texture.create_view();
 
-        stage.bindings.map_composite_layers(composite_layers);
-        stage.buffers.load(&stage.bindings);
+        let (bindings, buffers) = stage.next_buffers(composite_layers.len());
+        bindings.map_composite_layers(composite_layers);
+        buffers.load(bindings);
+        let count = bindings.count;
+
+        let composite_view = wgpu::BindingResource::TextureView(&composite_view);
+        let textures_view = textures.create_array_view();
+        let masks_view = masks.create_array_view();
+        let mut entries = vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: composite_view,
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&textures_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: buffers.layers.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: buffers.masks.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: buffers.blends.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: buffers.opacities.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: wgpu::BindingResource::TextureView(&masks_view),
+            },
+        ];
+        if !pipeline.push_constants {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 7,
+                resource: buffers.count.as_entire_binding(),
+            });
+        }
 
         let blending_bind_group = self
             .dev
             .device
             .create_bind_group(&wgpu::BindGroupDescriptor {
                 layout: &pipeline.blending_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&composite_view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::TextureView(&textures.create_view()),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: stage.buffers.layers.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: stage.buffers.masks.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 4,
-                        resource: stage.buffers.blends.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 5,
-                        resource: stage.buffers.opacities.as_entire_binding(),
-                    },
-                ],
+                entries: &entries,
                 label: Some("mixing_bind_group"),
             });
 
-        let output_view = stage.texture.create_view();
         let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: None,
             color_attachments: &[
@@ -411,12 +740,11 @@ impl CompositorTarget {
 
         // Finish and set the render pass's binding groups and data
         pass.set_pipeline(&pipeline.render_pipeline);
-        // We use push constants for the binding count.
-        pass.set_push_constants(
-            wgpu::ShaderStages::FRAGMENT,
-            0,
-            &stage.bindings.count.to_ne_bytes(),
-        );
+        // We use push constants for the binding count if available,
+        // otherwise it has already been loaded into a uniform buffer.
+        if pipeline.push_constants {
+            pass.set_push_constants(wgpu::ShaderStages::FRAGMENT, 0, &count.to_ne_bytes());
+        }
         pass.set_bind_group(0, &pipeline.constant_bind_group, &[]);
         pass.set_bind_group(1, &blending_bind_group, &[]);
         pass.set_vertex_buffer(0, self.data.vertex_buffer.slice(..));
@@ -428,6 +756,8 @@ impl CompositorTarget {
 }
 
 pub struct CompositorPipeline {
+    precision: Precision,
+    push_constants: bool,
     constant_bind_group: wgpu::BindGroup,
     blending_bind_group_layout: wgpu::BindGroupLayout,
     render_pipeline: wgpu::RenderPipeline,
@@ -436,18 +766,45 @@ pub struct CompositorPipeline {
 impl CompositorPipeline {
     /// Create a new compositor pipeline.
     pub fn new(dev: &GpuHandle) -> Self {
+        Self::with_precision(dev, Precision::default())
+    }
+
+    /// Create a new compositor pipeline rendering at the given precision.
+    pub fn with_precision(dev: &GpuHandle, precision: Precision) -> Self {
+        Self::with_options(
+            dev,
+            PipelineOptions {
+                precision,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Create a new compositor pipeline with the given options.
+    pub fn with_options(dev: &GpuHandle, options: PipelineOptions) -> Self {
+        let PipelineOptions {
+            precision,
+            blend_space,
+        } = options;
         let device = &dev.device;
 
         // This bind group only binds the sampler, which is a constant
-        // through out all rendering passes.
+        // through out all rendering passes. It filters so that outputs
+        // smaller or larger than the layers are smoothly resampled, which
+        // changes nothing when rendering at the document size.
         let (constant_bind_group_layout, constant_bind_group) = {
-            let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+            let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+                label: Some("compositor_sampler"),
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            });
             let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("texture_bind_group_layout"),
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
                     visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 }],
             });
@@ -492,52 +849,80 @@ impl CompositorPipeline {
                     ty: wgpu::BindingType::Texture {
                         multisampled: false,
                         view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
                     },
                     count,
                 }
             }
 
+            const fn fragment_bgl_tex_array_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+                wgpu::BindGroupLayoutEntry {
+                    binding,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                }
+            }
+
+            let mut entries = vec![
+                // composite
+                fragment_bgl_tex_entry(0, None),
+                // textures
+                fragment_bgl_tex_array_entry(1),
+                // layers
+                fragment_bgl_buffer_ro_entry(2, None),
+                // masks
+                fragment_bgl_buffer_ro_entry(3, None),
+                // blends
+                fragment_bgl_buffer_ro_entry(4, None),
+                // opacities
+                fragment_bgl_buffer_ro_entry(5, None),
+                // mask textures
+                fragment_bgl_tex_array_entry(6),
+            ];
+            // layer count, when push constants are unavailable
+            if !dev.push_constants {
+                entries.push(wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                });
+            }
+
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("blending_group_layout"),
-                entries: &[
-                    // composite
-                    fragment_bgl_tex_entry(0, None),
-                    // textures
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2Array,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                        },
-                        count: None,
-                    },
-                    // layers
-                    fragment_bgl_buffer_ro_entry(2, None),
-                    // masks
-                    fragment_bgl_buffer_ro_entry(3, None),
-                    // blends
-                    fragment_bgl_buffer_ro_entry(4, None),
-                    // opacities
-                    fragment_bgl_buffer_ro_entry(5, None),
-                ],
+                entries: &entries,
             })
         };
 
         // Loads the shader and creates the render pipeline.
         let render_pipeline = {
-            let shader = device.create_shader_module(shader_load());
+            let shader = device.create_shader_module(shader_load(
+                dev.push_constants,
+                blend_space == BlendColorSpace::Linear,
+            ));
 
             let render_pipeline_layout =
                 device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     label: Some("render_pipeline_layout"),
                     bind_group_layouts: &[&constant_bind_group_layout, &blending_bind_group_layout],
-                    push_constant_ranges: &[wgpu::PushConstantRange {
-                        stages: wgpu::ShaderStages::FRAGMENT,
-                        range: 0..4,
-                    }],
+                    push_constant_ranges: if dev.push_constants {
+                        &[wgpu::PushConstantRange {
+                            stages: wgpu::ShaderStages::FRAGMENT,
+                            range: 0..4,
+                        }]
+                    } else {
+                        &[]
+                    },
                 });
             device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
                 label: Some("render_pipeline"),
@@ -553,13 +938,13 @@ impl CompositorPipeline {
                     targets: &[
                         // Used to clear a background color
                         Some(wgpu::ColorTargetState {
-                            format: tex::TEX_FORMAT,
+                            format: precision.format(),
                             blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                             write_mask: wgpu::ColorWrites::ALL,
                         }),
                         // Used to blend the shader
                         Some(wgpu::ColorTargetState {
-                            format: tex::TEX_FORMAT,
+                            format: precision.format(),
                             blend: Some(wgpu::BlendState::REPLACE),
                             write_mask: wgpu::ColorWrites::ALL,
                         }),
@@ -581,43 +966,166 @@ impl CompositorPipeline {
         };
 
         Self {
+            precision,
+            push_constants: dev.push_constants,
             constant_bind_group,
             blending_bind_group_layout,
             render_pipeline,
         }
     }
+
+    /// Precision of the textures this pipeline renders to.
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
 }
 
+/// Declaration of the layer count in the shader source.
+const PUSH_CONSTANT_DECL: &str = "var<push_constant> layer_count: i32;";
+/// Replacement declaration of the layer count when push constants
+/// are unavailable. Matches the binding in the blending bind group.
+const UNIFORM_DECL: &str = "@group(1) @binding(7)\nvar<uniform> layer_count: i32;";
+/// Declaration of the blending color space in the shader source.
+const GAMMA_BLEND_DECL: &str = "const LINEAR_BLEND: bool = false;";
+/// Replacement declaration when blending in linear light.
+const LINEAR_BLEND_DECL: &str = "const LINEAR_BLEND: bool = true;";
+
 /// Load the shader.
-fn shader_load() -> wgpu::ShaderModuleDescriptor<'static> {
+fn shader_load(push_constants: bool, linear_blend: bool) -> wgpu::ShaderModuleDescriptor<'static> {
     // In release mode, the final binary includes the file directly so that
     // the binary does not rely on the shader file being at a specific location.
     #[cfg(not(debug_assertions))]
-    {
-        wgpu::include_wgsl!("../shader.wgsl")
-    }
+    let source = include_str!("../shader.wgsl").to_string();
     // In debug mode, this reads directly from a file so that recompilation
     // will not be necessary in the event that only the shader file changes.
     #[cfg(debug_assertions)]
-    {
-        wgpu::ShaderModuleDescriptor {
-            label: Some("Dynamically loaded shader module"),
-            source: wgpu::ShaderSource::Wgsl({
-                use std::fs::OpenOptions;
-                use std::io::Read;
-                // Get the path to the current crate's root directory
-                let crate_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-                let shader_path = crate_dir.join("src").join("shader.wgsl");
-
-                let mut file = OpenOptions::new()
-                    .read(true)
-                    .open(shader_path)
-                    .unwrap();
-
-                let mut buf = String::new();
-                file.read_to_string(&mut buf).unwrap();
-                buf.into()
-            }),
+    let source = {
+        use std::fs::OpenOptions;
+        use std::io::Read;
+        // Get the path to the current crate's root directory
+        let crate_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let shader_path = crate_dir.join("src").join("shader.wgsl");
+
+        let mut file = OpenOptions::new().read(true).open(shader_path).unwrap();
+
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        buf
+    };
+
+    let source = if push_constants {
+        source
+    } else {
+        source.replace(PUSH_CONSTANT_DECL, UNIFORM_DECL)
+    };
+    let source = if linear_blend {
+        source.replace(GAMMA_BLEND_DECL, LINEAR_BLEND_DECL)
+    } else {
+        source
+    };
+
+    wgpu::ShaderModuleDescriptor {
+        label: Some("Compositor shader module"),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Layer textures split into arrays of 4 layers. Splitting passes only
+    /// looks at where layers are, so no texture is needed.
+    fn textures() -> LayerTextures {
+        LayerTextures {
+            chunk: 4,
+            arrays: Vec::new(),
+        }
+    }
+
+    fn layer(texture: u32, clipped: Option<u32>) -> CompositeLayer {
+        CompositeLayer {
+            texture,
+            clipped,
+            opacity: 1.0,
+            blend: BlendingMode::Normal,
+        }
+    }
+
+    #[test]
+    fn unpad_odd_width() {
+        let dim = BufferDimensions::new(33, 3);
+        assert_eq!(dim.unpadded_bytes_per_row, 33 * 4);
+        assert_eq!(dim.padded_bytes_per_row, 256);
+
+        // Each row is filled with its row number, and its padding with 0xFF.
+        let data = (0..dim.height)
+            .flat_map(|row| {
+                let mut padded = vec![row as u8; dim.unpadded_bytes_per_row as usize];
+                padded.resize(dim.padded_bytes_per_row as usize, 0xFF);
+                padded
+            })
+            .collect::<Vec<_>>();
+
+        let unpadded = dim.unpad(&data);
+        assert_eq!(unpadded.len(), 33 * 3 * 4);
+        for (row, pixels) in unpadded.chunks(33 * 4).enumerate() {
+            assert!(pixels.iter().all(|&byte| byte == row as u8));
         }
     }
+
+    #[test]
+    fn unpad_aligned_width() {
+        let dim = BufferDimensions::new(64, 2);
+        assert_eq!(dim.unpadded_bytes_per_row, dim.padded_bytes_per_row);
+
+        let data = (0..64 * 2 * 4).map(|byte| byte as u8).collect::<Vec<_>>();
+        assert_eq!(dim.unpad(&data), data);
+    }
+
+    #[test]
+    fn locate_at_array_boundaries() {
+        let textures = textures();
+        assert_eq!(textures.locate(0), (0, 0));
+        assert_eq!(textures.locate(3), (0, 3));
+        assert_eq!(textures.locate(4), (1, 0));
+        assert_eq!(textures.locate(5), (1, 1));
+    }
+
+    #[test]
+    fn split_passes_at_array_boundaries() {
+        let layers = [
+            layer(0, None),
+            layer(3, Some(0)),
+            layer(4, None),
+            layer(5, Some(4)),
+            // Clipped onto a mask in the previous array.
+            layer(6, Some(3)),
+        ];
+        let passes = CompositePass::split(&layers, &textures());
+        let summary: Vec<_> = passes
+            .iter()
+            .map(|pass| {
+                let layers: Vec<_> = pass
+                    .layers
+                    .iter()
+                    .map(|layer| (layer.texture, layer.clipped))
+                    .collect();
+                (pass.textures, pass.masks, layers)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0, 0, vec![(0, None), (3, Some(0))]),
+                (1, 1, vec![(0, None), (1, Some(0))]),
+                (1, 0, vec![(2, Some(3))]),
+            ]
+        );
+
+        // The background is still drawn without any layer.
+        let passes = CompositePass::split(&[], &textures());
+        assert_eq!(passes.len(), 1);
+        assert!(passes[0].layers.is_empty());
+    }
 }
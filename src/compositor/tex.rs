@@ -1,14 +1,69 @@
-use image::{ImageBuffer, Rgba};
+use image::{DynamicImage, ImageBuffer};
 
 use super::{dev::GpuHandle, BufferDimensions};
+use crate::procreate::ProcreateError;
 
 const TEX_DIM: wgpu::TextureDimension = wgpu::TextureDimension::D2;
 pub(super) const TEX_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
 
+/// Layer textures of a document, split across as many texture arrays as
+/// the device's `max_texture_array_layers` limit requires.
+#[derive(Debug)]
+pub struct LayerTextures {
+    /// Number of layers held by every array except possibly the last.
+    pub chunk: u32,
+    /// Texture arrays, in layer index order.
+    pub arrays: Vec<GpuTexture>,
+}
+
+impl LayerTextures {
+    /// Create empty layer textures, allocating one texture array per
+    /// `max_texture_array_layers` layers.
+    pub fn new(
+        dev: &GpuHandle,
+        width: u32,
+        height: u32,
+        layers: u32,
+        usage: wgpu::TextureUsages,
+    ) -> Self {
+        let chunk = dev.device.limits().max_texture_array_layers.max(1);
+        let mut arrays = Vec::new();
+        let mut remaining = layers.max(1);
+        while remaining > 0 {
+            let count = remaining.min(chunk);
+            arrays.push(GpuTexture::empty_layers(dev, width, height, count, usage));
+            remaining -= count;
+        }
+
+        Self { chunk, arrays }
+    }
+
+    /// Resolve a layer index into the index of its texture array and the
+    /// layer index within that array.
+    pub fn locate(&self, index: u32) -> (usize, u32) {
+        ((index / self.chunk) as usize, index % self.chunk)
+    }
+
+    /// Replace a section of a layer with raw RGBA data.
+    /// See also [`GpuTexture::replace`].
+    pub fn replace(
+        &self,
+        dev: &GpuHandle,
+        position: (u32, u32),
+        size: (u32, u32),
+        layer: u32,
+        data: &[u8],
+    ) {
+        let (array, layer) = self.locate(layer);
+        self.arrays[array].replace(dev, position, size, layer, data);
+    }
+}
+
 /// GPU texture abstraction.
 #[derive(Debug)]
 pub struct GpuTexture {
     pub size: wgpu::Extent3d,
+    pub format: wgpu::TextureFormat,
     pub texture: wgpu::Texture,
 }
 
@@ -41,6 +96,16 @@ impl GpuTexture {
         dev: &GpuHandle,
         size: wgpu::Extent3d,
         usage: wgpu::TextureUsages,
+    ) -> Self {
+        Self::empty_with_format(dev, size, usage, TEX_FORMAT)
+    }
+
+    /// Create an empty texture from an extent and a texture format.
+    pub fn empty_with_format(
+        dev: &GpuHandle,
+        size: wgpu::Extent3d,
+        usage: wgpu::TextureUsages,
+        format: wgpu::TextureFormat,
     ) -> Self {
         // Canvas texture
         let texture = dev.device.create_texture(&wgpu::TextureDescriptor {
@@ -48,16 +113,24 @@ impl GpuTexture {
             mip_level_count: 1,
             sample_count: 1,
             dimension: TEX_DIM,
-            format: TEX_FORMAT,
-            view_formats: &[
-                wgpu::TextureFormat::Rgba8Unorm,
-                wgpu::TextureFormat::Rgba8UnormSrgb,
-            ],
+            format,
+            view_formats: if format == TEX_FORMAT {
+                &[
+                    wgpu::TextureFormat::Rgba8Unorm,
+                    wgpu::TextureFormat::Rgba8UnormSrgb,
+                ]
+            } else {
+                &[]
+            },
             usage,
             label: None,
         });
 
-        Self { texture, size }
+        Self {
+            texture,
+            format,
+            size,
+        }
     }
 
     pub fn layers(&self) -> u32 {
@@ -70,6 +143,18 @@ impl GpuTexture {
             .create_view(&wgpu::TextureViewDescriptor::default())
     }
 
+    /// Make a texture array view of this GPU texture.
+    ///
+    /// ### Note
+    /// Unlike [`GpuTexture::create_view`], this is always a `D2Array` view,
+    /// even when the texture only has a single layer.
+    pub fn create_array_view(&self) -> wgpu::TextureView {
+        self.texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        })
+    }
+
     /// Clear the texture with a certain color.
     #[allow(dead_code)]
     pub fn clear(&self, dev: &GpuHandle, color: wgpu::Color) {
@@ -145,41 +230,66 @@ impl GpuTexture {
     /// `dev` should be the same device that created this texture
     /// in the first place.
     pub fn clone(&self, dev: &GpuHandle) -> Self {
-        let clone = Self::empty_with_extent(
+        let clone = Self::empty_with_format(
             dev,
             self.size,
             Self::OUTPUT_USAGE | wgpu::TextureUsages::COPY_DST,
+            self.format,
         );
 
         dev.queue.submit(Some({
             let mut encoder = dev
                 .device
                 .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
-            // Copy the data from the texture to the buffer
-            encoder.copy_texture_to_texture(
-                self.texture.as_image_copy(),
-                clone.texture.as_image_copy(),
-                self.size,
-            );
+            self.copy_to_texture(&mut encoder, &clone);
             encoder.finish()
         }));
 
         clone
     }
 
+    /// Record a copy of the texture into another texture of the same size
+    /// and format.
+    pub fn copy_to_texture(&self, encoder: &mut wgpu::CommandEncoder, texture: &GpuTexture) {
+        encoder.copy_texture_to_texture(
+            self.texture.as_image_copy(),
+            texture.texture.as_image_copy(),
+            self.size,
+        );
+    }
+
+    /// Record a copy of the texture into a staging buffer laid out
+    /// according to `dim`.
+    pub fn copy_to_buffer(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        buffer: &wgpu::Buffer,
+        dim: BufferDimensions,
+    ) {
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(dim.padded_bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            dim.extent,
+        );
+    }
+
     /// Export the texture to the given path.
+    ///
+    /// 8-bit textures are read back as `Rgba8` images, and float textures
+    /// as `Rgba32F` images.
     pub async fn export_texture(
         &self,
         dev: &GpuHandle,
         dim: BufferDimensions,
-    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-        let output_buffer = dev.device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: (dim.padded_bytes_per_row * dim.height) as u64,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            // Copying texture to buffer requires that the buffer is not mapped
-            mapped_at_creation: false,
-        });
+    ) -> Result<DynamicImage, ProcreateError> {
+        let output_buffer = create_staging_buffer(dev, dim);
 
         // Copy the texture to the output buffer
         dev.queue.submit(Some({
@@ -188,38 +298,125 @@ impl GpuTexture {
                 .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
 
             // Copy the data from the texture to the buffer
-            encoder.copy_texture_to_buffer(
-                self.texture.as_image_copy(),
-                wgpu::ImageCopyBuffer {
-                    buffer: &output_buffer,
-                    layout: wgpu::ImageDataLayout {
-                        offset: 0,
-                        bytes_per_row: Some(dim.padded_bytes_per_row),
-                        rows_per_image: None,
-                    },
-                },
-                dim.extent,
-            );
+            self.copy_to_buffer(&mut encoder, &output_buffer, dim);
 
             encoder.finish()
         }));
 
-        let buffer_slice = output_buffer.slice(..);
+        let receiver = map_staging_buffer(&output_buffer);
+
+        dev.device.poll(wgpu::Maintain::Wait);
+        read_staging_buffer(&output_buffer, receiver, dim, self.format).await
+    }
+}
+
+/// Pool of staging buffers, so that several textures of the same
+/// dimensions can be copied in one go and read back concurrently.
+#[derive(Debug, Default)]
+pub struct StagingBuffers {
+    layout: Option<(BufferDimensions, wgpu::TextureFormat)>,
+    buffers: Vec<wgpu::Buffer>,
+}
 
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        buffer_slice.map_async(wgpu::MapMode::Read, move |result| tx.send(result).unwrap());
+impl StagingBuffers {
+    /// Make sure there are at least `count` staging buffers for
+    /// textures of the given dimensions and format.
+    pub fn reserve(
+        &mut self,
+        dev: &GpuHandle,
+        dim: BufferDimensions,
+        format: wgpu::TextureFormat,
+        count: usize,
+    ) {
+        if self.layout != Some((dim, format)) {
+            self.layout = Some((dim, format));
+            self.buffers.clear();
+        }
+
+        while self.buffers.len() < count {
+            self.buffers.push(create_staging_buffer(dev, dim));
+        }
+    }
+
+    /// Staging buffer at the given slot.
+    ///
+    /// ### Note
+    /// The slot must have been reserved beforehand.
+    pub fn buffer(&self, slot: usize) -> &wgpu::Buffer {
+        &self.buffers[slot]
+    }
+
+    /// Map the first `count` staging buffers at once and read them back.
+    pub async fn read(
+        &self,
+        dev: &GpuHandle,
+        count: usize,
+    ) -> Result<Vec<DynamicImage>, ProcreateError> {
+        let (dim, format) = self.layout.ok_or(ProcreateError::Readback)?;
+        let buffers = &self.buffers[..count];
+        let receivers = buffers.iter().map(map_staging_buffer).collect::<Vec<_>>();
 
         dev.device.poll(wgpu::Maintain::Wait);
-        rx.await.unwrap().expect("Buffer mapping failed");
 
-        let data = buffer_slice.get_mapped_range().to_vec();
-        output_buffer.unmap();
+        // Every buffer is read, even after a failure, so that none
+        // of them is left mapped for the next batch.
+        let mut images = Vec::with_capacity(count);
+        for (buffer, receiver) in buffers.iter().zip(receivers) {
+            images.push(read_staging_buffer(buffer, receiver, dim, format).await);
+        }
+        images.into_iter().collect()
+    }
+}
+
+type MapReceiver = tokio::sync::oneshot::Receiver<Result<(), wgpu::BufferAsyncError>>;
 
-        image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(
-            dim.padded_bytes_per_row / 4,
-            dim.height,
-            data,
-        )
-        .unwrap()
+/// Create a buffer that a texture of the given dimensions can be
+/// copied into and read back from.
+fn create_staging_buffer(dev: &GpuHandle, dim: BufferDimensions) -> wgpu::Buffer {
+    dev.device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: (dim.padded_bytes_per_row * dim.height) as u64,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        // Copying texture to buffer requires that the buffer is not mapped
+        mapped_at_creation: false,
+    })
+}
+
+/// Request a staging buffer to be mapped. The mapping only completes
+/// once the device is polled.
+fn map_staging_buffer(buffer: &wgpu::Buffer) -> MapReceiver {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    buffer
+        .slice(..)
+        .map_async(wgpu::MapMode::Read, move |result| {
+            // The receiver is only gone if the export was abandoned.
+            let _ = tx.send(result);
+        });
+    rx
+}
+
+/// Wait for a staging buffer to be mapped, then copy its contents without
+/// the row padding into an image and unmap it.
+async fn read_staging_buffer(
+    buffer: &wgpu::Buffer,
+    receiver: MapReceiver,
+    dim: BufferDimensions,
+    format: wgpu::TextureFormat,
+) -> Result<DynamicImage, ProcreateError> {
+    receiver.await.map_err(|_| ProcreateError::Readback)??;
+
+    let data = dim.unpad(&buffer.slice(..).get_mapped_range());
+    buffer.unmap();
+
+    match format {
+        wgpu::TextureFormat::Rgba16Float => {
+            let data = data
+                .chunks_exact(2)
+                .map(|bytes| half::f16::from_ne_bytes([bytes[0], bytes[1]]).to_f32())
+                .collect();
+            ImageBuffer::from_raw(dim.width, dim.height, data).map(DynamicImage::ImageRgba32F)
+        }
+        _ => ImageBuffer::from_raw(dim.width, dim.height, data).map(DynamicImage::ImageRgba8),
     }
+    .ok_or(ProcreateError::Readback)
 }
@@ -0,0 +1,633 @@
+//! Krita document export, which keeps the layer hierarchy editable.
+//!
+//! A `.kra` file is a zip archive holding a `maindoc.xml` description of
+//! the layer tree, and the pixels of every paint layer in Krita's tiled
+//! paint device format.
+
+use super::Rect;
+use crate::app::ExportedImage;
+use crate::procreate::{BlendingMode, ProcreateError, ProcreateFile};
+use crate::procreate::{SilicaGroup, SilicaHierarchy, SilicaLayer};
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{Cursor, Seek, Write};
+use zip::write::{FileOptions, ZipWriter};
+use zip::CompressionMethod;
+
+/// Size of the square tiles of Krita paint devices.
+const TILE_SIZE: u32 = 64;
+/// Size of the thumbnail Krita shows in its file dialogs.
+const PREVIEW_SIZE: u32 = 256;
+
+/// Map a Procreate blending mode to the closest Krita composite op.
+fn composite_op(blend: BlendingMode) -> &'static str {
+    match blend {
+        BlendingMode::Normal => "normal",
+        BlendingMode::Multiply => "multiply",
+        BlendingMode::Screen => "screen",
+        BlendingMode::Add => "add",
+        BlendingMode::Lighten => "lighten",
+        BlendingMode::Exclusion => "exclusion",
+        BlendingMode::Difference => "diff",
+        BlendingMode::Subtract => "subtract",
+        BlendingMode::LinearBurn => "linear_burn",
+        BlendingMode::ColorDodge => "dodge",
+        BlendingMode::ColorBurn => "burn",
+        BlendingMode::Overlay => "overlay",
+        BlendingMode::HardLight => "hard_light",
+        BlendingMode::Color => "color",
+        BlendingMode::Luminosity => "luminize",
+        BlendingMode::Hue => "hue",
+        BlendingMode::Saturation => "saturation",
+        BlendingMode::SoftLight => "soft_light",
+        BlendingMode::Darken => "darken",
+        BlendingMode::HardMix => "hard_mix_photoshop",
+        BlendingMode::VividLight => "vivid_light",
+        BlendingMode::LinearLight => "linear light",
+        BlendingMode::PinLight => "pin_light",
+        BlendingMode::LighterColor => "lighter color",
+        BlendingMode::DarkerColor => "darker color",
+        BlendingMode::Divide => "divide",
+    }
+}
+
+/// Writer of a Krita document.
+///
+/// Layer pixels are added one layer at a time with [`KraWriter::push`],
+/// from images exported by [`crate::app::App::export_layer_pixels`] with
+/// straight alpha. The layer tree is written last, by
+/// [`KraWriter::finish`].
+pub struct KraWriter<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    width: u32,
+    height: u32,
+    /// Name of the image, which is also the folder of its layer files.
+    name: String,
+    author: Option<String>,
+    layers: SilicaGroup,
+    background_color: [f32; 4],
+    background_hidden: bool,
+    /// File name of the pixels of each layer written so far, by UUID.
+    files: HashMap<String, String>,
+    next_file: usize,
+}
+
+impl<W: Write + Seek> KraWriter<W> {
+    /// Start writing a Krita document for `file`, whose canvas is `width`
+    /// by `height` pixels once rotated.
+    pub fn new(
+        writer: W,
+        file: &ProcreateFile,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, ProcreateError> {
+        let mut zip = ZipWriter::new(writer);
+        // Krita recognises documents by this first, uncompressed entry.
+        zip.start_file(
+            "mimetype",
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )?;
+        zip.write_all(b"application/x-krita")?;
+
+        let name = file
+            .name
+            .as_deref()
+            .map(|name| name.trim().replace(['/', '\\'], "_"))
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| String::from("Untitled"));
+
+        Ok(Self {
+            zip,
+            width,
+            height,
+            name,
+            author: file.author_name.clone(),
+            layers: file.layers.clone(),
+            background_color: file.background_color,
+            background_hidden: file.background_hidden,
+            files: HashMap::new(),
+            next_file: 1,
+        })
+    }
+
+    /// Write the pixels of an exported layer.
+    pub fn push(&mut self, image: &ExportedImage) -> Result<(), ProcreateError> {
+        if image.layer.group {
+            return Err(ProcreateError::Unsupported(
+                "Krita layers only take the pixels of single layers",
+            ));
+        }
+        let file = self.write_pixels(&image.image.to_rgba8(), image.bounds)?;
+        self.files.insert(image.layer.uuid.clone(), file);
+        Ok(())
+    }
+
+    /// Write the layer tree, along with the composite as the merged image
+    /// other applications show, and finish the document. Layers whose
+    /// pixels were never pushed are left empty.
+    pub fn finish(mut self, composite: Option<&DynamicImage>) -> Result<W, ProcreateError> {
+        let layers = std::mem::replace(&mut self.layers, SilicaGroup::empty());
+        let mut xml = String::new();
+        self.write_nodes(&mut xml, &nodes(&layers), 2)?;
+
+        // Procreate backgrounds are a plain color, so they become the
+        // bottom layer, hidden when the background is.
+        let pixel = self
+            .background_color
+            .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+        let background = RgbaImage::from_pixel(self.width, self.height, Rgba(pixel));
+        let bounds = Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        let file = self.write_pixels(&background, bounds)?;
+        let element = LayerElement {
+            name: "Background",
+            visible: !self.background_hidden,
+            opacity: 1.0,
+            blend: BlendingMode::Normal,
+            inherit_alpha: false,
+            uuid: None,
+        };
+        element.open(&mut xml, 2, &file, "paintlayer");
+        xml.push_str(" colorspacename=\"RGBA\"/>\n");
+
+        let maindoc = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE DOC PUBLIC '-//KDE//DTD krita 2.0//EN' \
+             'http://www.calligra.org/DTD/krita-2.0.dtd'>\n\
+             <DOC xmlns=\"http://www.calligra.org/DTD/krita\" syntaxVersion=\"2\" \
+             editor=\"Krita\" kritaVersion=\"5.2.0\">\n \
+             <IMAGE name=\"{name}\" mime=\"application/x-kra\" width=\"{width}\" \
+             height=\"{height}\" colorspacename=\"RGBA\" profile=\"sRGB-elle-V2-srgbtrc.icc\" \
+             x-res=\"72\" y-res=\"72\" description=\"\">\n  \
+             <layers>\n{xml}  </layers>\n \
+             </IMAGE>\n\
+             </DOC>\n",
+            name = escape_xml(&self.name),
+            width = self.width,
+            height = self.height,
+        );
+        self.start_file("maindoc.xml")?;
+        self.zip.write_all(maindoc.as_bytes())?;
+
+        let info = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE document-info PUBLIC '-//KDE//DTD document-info 1.1//EN' \
+             'http://www.calligra.org/DTD/document-info-1.1.dtd'>\n\
+             <document-info xmlns=\"http://www.calligra.org/DTD/document-info\">\n \
+             <about>\n  <title>{title}</title>\n </about>\n \
+             <author>\n  <full-name>{author}</full-name>\n </author>\n\
+             </document-info>\n",
+            title = escape_xml(&self.name),
+            author = escape_xml(self.author.as_deref().unwrap_or_default()),
+        );
+        self.start_file("documentinfo.xml")?;
+        self.zip.write_all(info.as_bytes())?;
+
+        if let Some(composite) = composite {
+            let preview = composite.thumbnail(PREVIEW_SIZE, PREVIEW_SIZE);
+            for (path, image) in [("mergedimage.png", composite), ("preview.png", &preview)] {
+                let mut buf = Cursor::new(Vec::new());
+                DynamicImage::ImageRgba8(image.to_rgba8())
+                    .write_to(&mut buf, ImageOutputFormat::Png)?;
+                self.start_file(path)?;
+                self.zip.write_all(buf.get_ref())?;
+            }
+        }
+
+        Ok(self.zip.finish()?)
+    }
+
+    /// Write the `<layer>` elements of nodes listed from the bottom up,
+    /// which Krita lists from the top down.
+    fn write_nodes(
+        &mut self,
+        xml: &mut String,
+        nodes: &[Node<'_>],
+        depth: usize,
+    ) -> Result<(), ProcreateError> {
+        for (index, node) in nodes.iter().enumerate().rev() {
+            match node {
+                Node::Layer(layer) => {
+                    self.write_paint_layer(xml, depth, LayerElement::of_layer(layer))?
+                }
+                Node::Group(group, children) => {
+                    let element = LayerElement {
+                        name: group.name.as_deref().unwrap_or("Group"),
+                        visible: !group.hidden,
+                        opacity: 1.0,
+                        blend: BlendingMode::Normal,
+                        inherit_alpha: false,
+                        uuid: group.uuid.as_deref(),
+                    };
+                    // Procreate blends the layers of groups with the
+                    // layers around them, rather than on their own.
+                    self.write_group(xml, depth, element, true, |this, xml| {
+                        this.write_nodes(xml, children, depth + 2)
+                    })?;
+                }
+                // Krita clips layers to everything below them in their
+                // group, so clipped layers with more than their base below
+                // them get a group of their own. The group itself leaves the
+                // pixels alone, and the base keeps its own blending, which
+                // Krita applies to the layers clipped onto it as well.
+                Node::Clip(base, clipped) if index > 0 => {
+                    let element = LayerElement {
+                        name: base.name.as_deref().unwrap_or("Layer"),
+                        visible: !base.hidden,
+                        opacity: 1.0,
+                        blend: BlendingMode::Normal,
+                        inherit_alpha: false,
+                        uuid: None,
+                    };
+                    self.write_group(xml, depth, element, false, |this, xml| {
+                        this.write_clip(xml, depth + 2, LayerElement::of_layer(base), clipped)
+                    })?;
+                }
+                Node::Clip(base, clipped) => {
+                    self.write_clip(xml, depth, LayerElement::of_layer(base), clipped)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a base layer and the layers clipped onto it.
+    fn write_clip(
+        &mut self,
+        xml: &mut String,
+        depth: usize,
+        base: LayerElement<'_>,
+        clipped: &[&SilicaLayer],
+    ) -> Result<(), ProcreateError> {
+        for layer in clipped.iter().rev() {
+            let element = LayerElement {
+                inherit_alpha: true,
+                ..LayerElement::of_layer(layer)
+            };
+            self.write_paint_layer(xml, depth, element)?;
+        }
+        self.write_paint_layer(xml, depth, base)
+    }
+
+    fn write_paint_layer(
+        &mut self,
+        xml: &mut String,
+        depth: usize,
+        element: LayerElement<'_>,
+    ) -> Result<(), ProcreateError> {
+        let pushed = element.uuid.and_then(|uuid| self.files.get(uuid)).cloned();
+        let file = match pushed {
+            Some(file) => file,
+            None => {
+                let empty = Rect {
+                    x: 0,
+                    y: 0,
+                    width: 0,
+                    height: 0,
+                };
+                self.write_pixels(&RgbaImage::new(0, 0), empty)?
+            }
+        };
+        element.open(xml, depth, &file, "paintlayer");
+        xml.push_str(" colorspacename=\"RGBA\"/>\n");
+        Ok(())
+    }
+
+    fn write_group(
+        &mut self,
+        xml: &mut String,
+        depth: usize,
+        element: LayerElement<'_>,
+        passthrough: bool,
+        children: impl FnOnce(&mut Self, &mut String) -> Result<(), ProcreateError>,
+    ) -> Result<(), ProcreateError> {
+        let file = self.next_file_name();
+        element.open(xml, depth, &file, "grouplayer");
+        let indent = " ".repeat(depth);
+        let _ = writeln!(
+            xml,
+            " passthrough=\"{}\" collapsed=\"0\">\n{indent} <layers>",
+            u8::from(passthrough)
+        );
+        children(self, xml)?;
+        let _ = writeln!(xml, "{indent} </layers>\n{indent}</layer>");
+        Ok(())
+    }
+
+    /// Write the pixels of a paint layer, and return the name of their file.
+    fn write_pixels(&mut self, image: &RgbaImage, bounds: Rect) -> Result<String, ProcreateError> {
+        let file = self.next_file_name();
+        let path = format!("{}/layers/{file}", self.name);
+        self.start_file(&path)?;
+        self.zip.write_all(&encode_tiles(image, bounds))?;
+        Ok(file)
+    }
+
+    fn next_file_name(&mut self) -> String {
+        let file = format!("layer{}", self.next_file);
+        self.next_file += 1;
+        file
+    }
+
+    fn start_file(&mut self, path: &str) -> Result<(), ProcreateError> {
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        Ok(self.zip.start_file(path, options)?)
+    }
+}
+
+/// Node of the layer tree of a Krita document.
+enum Node<'a> {
+    Layer(&'a SilicaLayer),
+    /// A group, with its children from the bottom up.
+    Group(&'a SilicaGroup, Vec<Node<'a>>),
+    /// A base layer, and the layers clipped onto it from the bottom up.
+    Clip(&'a SilicaLayer, Vec<&'a SilicaLayer>),
+}
+
+/// Nodes of the children of a group, from the bottom up.
+fn nodes(group: &SilicaGroup) -> Vec<Node<'_>> {
+    let mut siblings: Vec<Node<'_>> = Vec::new();
+    for child in group.children.iter().rev() {
+        match child {
+            SilicaHierarchy::Group(group) => siblings.push(Node::Group(group, nodes(group))),
+            SilicaHierarchy::Layer(layer) if layer.clipped => match siblings.last_mut() {
+                Some(Node::Layer(base)) => {
+                    let base = *base;
+                    *siblings.last_mut().unwrap() = Node::Clip(base, vec![layer]);
+                }
+                Some(Node::Clip(_, clipped)) => clipped.push(layer),
+                // Without a layer to clip onto, the layer is not clipped.
+                _ => siblings.push(Node::Layer(layer)),
+            },
+            SilicaHierarchy::Layer(layer) => siblings.push(Node::Layer(layer)),
+        }
+    }
+    siblings
+}
+
+/// Attributes shared by every `<layer>` element.
+struct LayerElement<'a> {
+    name: &'a str,
+    visible: bool,
+    opacity: f32,
+    blend: BlendingMode,
+    /// Whether the layer only shows where the layers below it do, which
+    /// is how Krita clips layers.
+    inherit_alpha: bool,
+    uuid: Option<&'a str>,
+}
+
+impl<'a> LayerElement<'a> {
+    fn of_layer(layer: &'a SilicaLayer) -> Self {
+        Self {
+            name: layer.name.as_deref().unwrap_or("Layer"),
+            visible: !layer.hidden,
+            opacity: layer.opacity,
+            blend: layer.blend,
+            inherit_alpha: false,
+            uuid: Some(&layer.uuid),
+        }
+    }
+
+    /// Write the start of the element, up to its last shared attribute.
+    fn open(&self, xml: &mut String, depth: usize, file: &str, node_type: &str) {
+        let _ = write!(
+            xml,
+            "{:depth$}<layer name=\"{}\" filename=\"{file}\" nodetype=\"{node_type}\" \
+             visible=\"{}\" locked=\"0\" opacity=\"{}\" compositeop=\"{}\" \
+             inheritalpha=\"{}\" x=\"0\" y=\"0\"",
+            "",
+            escape_xml(self.name),
+            u8::from(self.visible),
+            (self.opacity.clamp(0.0, 1.0) * 255.0).round() as u8,
+            escape_xml(composite_op(self.blend)),
+            u8::from(self.inherit_alpha),
+        );
+        // Krita UUIDs are written in braces.
+        if let Some(uuid) = self.uuid.filter(|uuid| is_uuid(uuid)) {
+            let _ = write!(xml, " uuid=\"{{{uuid}}}\"");
+        }
+    }
+}
+
+fn is_uuid(uuid: &str) -> bool {
+    uuid.len() == 36
+        && uuid.chars().enumerate().all(|(i, c)| {
+            matches!(i, 8 | 13 | 18 | 23) == (c == '-') && (c == '-' || c.is_ascii_hexdigit())
+        })
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Encode an image covering `bounds` of the canvas as a Krita paint device.
+/// Tiles are stored uncompressed, since the zip archive compresses them
+/// anyway, and fully transparent tiles are left out.
+fn encode_tiles(image: &RgbaImage, bounds: Rect) -> Vec<u8> {
+    let tile_bytes = (TILE_SIZE * TILE_SIZE * 4) as usize;
+    let mut tiles = Vec::new();
+    let mut count = 0;
+
+    let columns = bounds.x / TILE_SIZE..(bounds.x + bounds.width).div_ceil(TILE_SIZE);
+    let rows = bounds.y / TILE_SIZE..(bounds.y + bounds.height).div_ceil(TILE_SIZE);
+    let mut tile = vec![0; tile_bytes];
+    for row in rows {
+        for column in columns.clone() {
+            tile.fill(0);
+            let mut empty = true;
+            for ty in 0..TILE_SIZE {
+                let y = row * TILE_SIZE + ty;
+                for tx in 0..TILE_SIZE {
+                    let x = column * TILE_SIZE + tx;
+                    let Some(pixel) = x
+                        .checked_sub(bounds.x)
+                        .zip(y.checked_sub(bounds.y))
+                        .and_then(|(x, y)| image.get_pixel_checked(x, y))
+                    else {
+                        continue;
+                    };
+                    let Rgba([r, g, b, a]) = *pixel;
+                    if a == 0 {
+                        continue;
+                    }
+                    // Krita stores 8-bit RGBA pixels as BGRA.
+                    let i = ((ty * TILE_SIZE + tx) * 4) as usize;
+                    tile[i..i + 4].copy_from_slice(&[b, g, r, a]);
+                    empty = false;
+                }
+            }
+            if empty {
+                continue;
+            }
+
+            count += 1;
+            // The data of each tile starts with whether it is compressed.
+            let header = format!(
+                "{},{},LZF,{}\n",
+                column * TILE_SIZE,
+                row * TILE_SIZE,
+                tile_bytes + 1
+            );
+            tiles.extend_from_slice(header.as_bytes());
+            tiles.push(0);
+            tiles.extend_from_slice(&tile);
+        }
+    }
+
+    let mut device = format!(
+        "VERSION 2\nTILEWIDTH {TILE_SIZE}\nTILEHEIGHT {TILE_SIZE}\nPIXELSIZE 4\nDATA {count}\n"
+    )
+    .into_bytes();
+    device.extend_from_slice(&tiles);
+    device
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procreate::fixtures::{self, layer};
+
+    #[test]
+    fn tiles() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(1, 0, Rgba([1, 2, 3, 4]));
+        let bounds = Rect {
+            x: 70,
+            y: 3,
+            width: 2,
+            height: 1,
+        };
+        let device = encode_tiles(&image, bounds);
+        let header =
+            "VERSION 2\nTILEWIDTH 64\nTILEHEIGHT 64\nPIXELSIZE 4\nDATA 1\n64,0,LZF,16385\n";
+        assert!(device.starts_with(header.as_bytes()));
+        let tile = &device[header.len() + 1..];
+        let i = (3 * 64 + 7) * 4;
+        assert_eq!(tile[i..i + 4], [3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn clipped_layers() {
+        let mut group = SilicaGroup {
+            hidden: false,
+            name: None,
+            uuid: None,
+            children: vec![
+                layer("Shading", true),
+                layer("Color", false),
+                layer("Shadow", true),
+                layer("Lines", true),
+                layer("Paper", false),
+            ],
+        };
+        for child in &mut group.children {
+            if let SilicaHierarchy::Layer(layer) = child {
+                layer.blend = BlendingMode::Multiply;
+                layer.opacity = 0.5;
+            }
+        }
+
+        let file = fixtures::file(group, 1, 1);
+        let mut kra = KraWriter::new(Cursor::new(Vec::new()), &file, 1, 1).unwrap();
+        let mut xml = String::new();
+        kra.write_nodes(&mut xml, &nodes(&file.layers), 0).unwrap();
+        let lines: Vec<_> = xml
+            .lines()
+            .map(|line| {
+                let name = line
+                    .split("name=\"")
+                    .nth(1)
+                    .map(|rest| &rest[..rest.find('"').unwrap()]);
+                (
+                    name.unwrap_or(line.trim()),
+                    line.contains("inheritalpha=\"1\""),
+                    line.contains("opacity=\"255\""),
+                )
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                ("Color", false, true),
+                ("<layers>", false, false),
+                ("Shading", true, false),
+                ("Color", false, false),
+                ("</layers>", false, false),
+                ("</layer>", false, false),
+                ("Shadow", true, false),
+                ("Lines", true, false),
+                ("Paper", false, false),
+            ]
+        );
+        // The group only holds the clip together; the base layer keeps its
+        // own blending.
+        let group = xml.lines().next().unwrap();
+        assert!(group.contains("compositeop=\"normal\""), "{group}");
+        assert!(!group.contains("uuid="), "{group}");
+        assert!(xml.contains("compositeop=\"multiply\""));
+        // Node UUIDs have to be unique, so the group standing in for the
+        // base layer has none of its own.
+        for child in &file.layers.children {
+            if let SilicaHierarchy::Layer(layer) = child {
+                let uuid = format!("uuid=\"{{{}}}\"", layer.uuid);
+                assert_eq!(xml.matches(&uuid).count(), 1, "{uuid}");
+            }
+        }
+    }
+
+    #[test]
+    fn group_uuids() {
+        let uuid = "6C1F0E3A-52B8-4D0C-9F1E-2A7B3C4D5E6F";
+        let group = SilicaGroup {
+            hidden: false,
+            name: None,
+            uuid: None,
+            children: vec![
+                SilicaHierarchy::Group(SilicaGroup {
+                    hidden: false,
+                    name: Some("Named".into()),
+                    uuid: Some(uuid.into()),
+                    children: vec![layer("Ink", false)],
+                }),
+                SilicaHierarchy::Group(SilicaGroup {
+                    hidden: false,
+                    name: Some("Unnamed".into()),
+                    uuid: None,
+                    children: vec![layer("Paper", false)],
+                }),
+            ],
+        };
+
+        let file = fixtures::file(group, 1, 1);
+        let mut kra = KraWriter::new(Cursor::new(Vec::new()), &file, 1, 1).unwrap();
+        let mut xml = String::new();
+        kra.write_nodes(&mut xml, &nodes(&file.layers), 0).unwrap();
+        let groups: Vec<_> = xml
+            .lines()
+            .filter(|line| line.contains("grouplayer"))
+            .collect();
+        assert_eq!(groups.len(), 2);
+        // Groups missing a UUID in the document are written without one.
+        assert!(groups[0].contains(&format!("uuid=\"{{{uuid}}}\"")));
+        assert!(groups[1].contains("name=\"Unnamed\""));
+        assert!(!groups[1].contains("uuid="));
+    }
+}
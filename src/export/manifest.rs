@@ -0,0 +1,165 @@
+//! JSON manifest describing where exported layer images belong, so that
+//! the layer stack can be reassembled from the exported files.
+
+use super::Rect;
+use crate::app::ExportedImage;
+use crate::procreate::{BlendingMode, ProcreateError, ProcreateFile};
+use serde::Serialize;
+
+/// Manifest of every image written by an export.
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    /// Canvas width, in pixels.
+    pub width: u32,
+    /// Canvas height, in pixels.
+    pub height: u32,
+    pub document: DocumentInfo,
+    /// Exported images, from the bottom layer up.
+    pub layers: Vec<ManifestEntry>,
+}
+
+/// Document metadata recorded in a manifest.
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentInfo {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub stroke_count: usize,
+    /// Background color, as straight alpha RGBA.
+    pub background_color: [f32; 4],
+    pub background_hidden: bool,
+    /// Number of quarter turns the canvas is rotated by.
+    pub orientation: u32,
+    pub flipped_horizontally: bool,
+    pub flipped_vertically: bool,
+}
+
+impl DocumentInfo {
+    pub fn new(file: &ProcreateFile) -> Self {
+        Self {
+            name: file.name.clone(),
+            author: file.author_name.clone(),
+            stroke_count: file.stroke_count,
+            background_color: file.background_color,
+            background_hidden: file.background_hidden,
+            orientation: file.orientation,
+            flipped_horizontally: file.flipped.horizontally,
+            flipped_vertically: file.flipped.vertically,
+        }
+    }
+}
+
+/// Manifest entry of a single exported image.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+    /// Path of the image, relative to the manifest.
+    pub file: String,
+    pub uuid: String,
+    pub name: Option<String>,
+    /// Names of the groups containing the layer, outermost first.
+    pub group_path: Vec<String>,
+    pub blend: BlendingMode,
+    pub opacity: f32,
+    pub hidden: bool,
+    pub clipped: bool,
+    /// Whether the image is a whole group flattened into one image.
+    pub group: bool,
+    /// Stacking order of the layer in the document, hidden layers
+    /// included, starting at 0 for the bottom layer. Groups share the
+    /// z-order of their topmost layer.
+    pub z_order: usize,
+    /// Area covered by the image on the canvas.
+    pub bounds: Rect,
+}
+
+impl Manifest {
+    /// Create an empty manifest for an export of `file`, whose output
+    /// images are `width` by `height` pixels before any trimming.
+    pub fn new(file: &ProcreateFile, width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            document: DocumentInfo::new(file),
+            layers: Vec::new(),
+        }
+    }
+
+    /// Record an exported image written to `file`.
+    pub fn push(&mut self, file: impl Into<String>, image: &ExportedImage) {
+        let layer = &image.layer;
+        self.layers.push(ManifestEntry {
+            file: file.into(),
+            uuid: layer.uuid.clone(),
+            name: layer.name.clone(),
+            group_path: layer.group_path.clone(),
+            blend: layer.blend,
+            opacity: layer.opacity,
+            hidden: layer.hidden,
+            clipped: layer.clipped,
+            group: layer.group,
+            z_order: layer.z_order,
+            bounds: image.bounds,
+        });
+    }
+
+    /// Serialize the manifest as pretty-printed JSON.
+    pub fn to_json(&self) -> Result<Vec<u8>, ProcreateError> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::fixtures::exported;
+    use crate::procreate::{fixtures, SilicaGroup};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn json_fields() {
+        let file = fixtures::file(SilicaGroup::empty(), 8, 6);
+        let mut manifest = Manifest::new(&file, 8, 6);
+        let mut layer = ExportedImage {
+            bounds: Rect {
+                x: 2,
+                y: 1,
+                width: 3,
+                height: 4,
+            },
+            ..exported(1, Some("Ink"), &["Line art", "Inks"])
+        };
+        layer.layer.hidden = true;
+        layer.layer.blend = BlendingMode::Multiply;
+        manifest.push("ink.png", &layer);
+        let mut group = exported(2, Some("Line art"), &[]);
+        group.layer.group = true;
+        manifest.push("line-art.png", &group);
+
+        let json: Value = serde_json::from_slice(&manifest.to_json().unwrap()).unwrap();
+        assert_eq!(json["width"], 8);
+        assert_eq!(json["height"], 6);
+        assert_eq!(json["document"]["background_hidden"], false);
+        let layers = json["layers"].as_array().unwrap();
+        assert_eq!(layers.len(), 2);
+
+        let ink = &layers[0];
+        assert_eq!(ink["file"], "ink.png");
+        assert_eq!(ink["uuid"], layer.layer.uuid);
+        assert_eq!(ink["name"], "Ink");
+        assert_eq!(ink["group_path"], json!(["Line art", "Inks"]));
+        assert_eq!(ink["blend"], "Multiply");
+        assert_eq!(ink["opacity"], 1.0);
+        assert_eq!(ink["hidden"], true);
+        assert_eq!(ink["clipped"], false);
+        assert_eq!(ink["group"], false);
+        assert_eq!(ink["z_order"], 1);
+        assert_eq!(
+            ink["bounds"],
+            json!({"x": 2, "y": 1, "width": 3, "height": 4})
+        );
+
+        let group = &layers[1];
+        assert_eq!(group["group"], true);
+        assert_eq!(group["hidden"], false);
+        assert_eq!(group["group_path"], json!([]));
+    }
+}
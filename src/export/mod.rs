@@ -0,0 +1,571 @@
+//! Turning rendered images into files.
+//!
+//! Single images are prepared with [`AlphaMode`] and [`trim`], and
+//! encoded by [`OutputFormat`] as PNG, TIFF, OpenEXR, JPEG, WebP, BMP or
+//! QOI. Whole layer stacks can instead be written into one layered file:
+//! a Krita document ([`KraWriter`]), a GIMP image ([`XcfWriter`]), a
+//! multi-part OpenEXR image ([`LayeredExr`]), a multi-page TIFF
+//! ([`MultiPageTiff`]) or a PDF ([`PdfWriter`]). Exports written as
+//! separate files are named with a [`NameTemplate`], and described by a
+//! JSON [`Manifest`] to reassemble the layer stack from.
+
+mod krita;
+mod manifest;
+mod multipage;
+mod naming;
+mod openexr;
+mod pdf;
+mod xcf;
+
+pub use self::krita::KraWriter;
+pub use self::manifest::{DocumentInfo, Manifest, ManifestEntry};
+pub use self::multipage::MultiPageTiff;
+pub use self::naming::{FileNamer, NameTemplate};
+pub use self::openexr::{ExrPrecision, LayeredExr};
+pub use self::pdf::PdfWriter;
+pub use self::xcf::{XcfModes, XcfWriter};
+
+use crate::procreate::ProcreateError;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, RgbImage};
+use rayon::prelude::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use rayon::slice::{ParallelSlice, ParallelSliceMut};
+use serde::Serialize;
+use std::io::Cursor;
+use tiff::encoder::{DirectoryEncoder, TiffEncoder, TiffKindStandard};
+use tiff::TiffResult;
+
+/// How alpha is stored in exported images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlphaMode {
+    /// Colors are not multiplied by alpha. This is what PNG and WebP
+    /// expect.
+    #[default]
+    Straight,
+    /// Colors are multiplied by alpha, as rendered by the compositor.
+    /// This is what compositing pipelines expect.
+    Premultiplied,
+}
+
+impl AlphaMode {
+    /// Convert an image rendered by the compositor, which is always
+    /// premultiplied, into this alpha mode.
+    pub fn apply(self, image: &mut DynamicImage) {
+        if self == Self::Premultiplied {
+            return;
+        }
+
+        // The compositor only reads back these two kinds of images.
+        match image {
+            DynamicImage::ImageRgba8(image) => {
+                let pixels: &mut [u8] = image;
+                pixels.par_chunks_exact_mut(4).for_each(unpremultiply);
+            }
+            DynamicImage::ImageRgba32F(image) => {
+                let pixels: &mut [f32] = image;
+                pixels.par_chunks_exact_mut(4).for_each(unpremultiply_f32);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Divide the color channels of a premultiplied RGBA pixel by its alpha.
+fn unpremultiply(pixel: &mut [u8]) {
+    let alpha = u32::from(pixel[3]);
+    if alpha == 0 || alpha == 255 {
+        return;
+    }
+    for channel in &mut pixel[..3] {
+        *channel = ((u32::from(*channel) * 255 + alpha / 2) / alpha).min(255) as u8;
+    }
+}
+
+/// Divide the color channels of a premultiplied float RGBA pixel by its alpha.
+fn unpremultiply_f32(pixel: &mut [f32]) {
+    let alpha = pixel[3];
+    if alpha <= 0.0 || alpha >= 1.0 {
+        return;
+    }
+    for channel in &mut pixel[..3] {
+        *channel = (*channel / alpha).min(1.0);
+    }
+}
+
+/// Bits per channel of exported integer images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    /// Only worth it when compositing at [`crate::compositor::Precision::Float16`].
+    Sixteen,
+}
+
+/// Compression of exported TIFF images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TiffCompression {
+    #[default]
+    None,
+    Lzw,
+    Deflate,
+    PackBits,
+}
+
+/// Compression of exported WebP images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WebpCompression {
+    #[default]
+    Lossless,
+    /// Lossy compression at a quality from 0 to 100. Only available with
+    /// the `webp-lossy` feature, which builds libwebp.
+    Lossy(u8),
+}
+
+/// Encoded image format of exported images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png(BitDepth),
+    Tiff(BitDepth, TiffCompression),
+    /// 32-bit float OpenEXR, with colors converted to linear light and
+    /// premultiplied by alpha. See [`LayeredExr`] for every layer in a
+    /// single EXR image.
+    OpenExr,
+    /// JPEG at a quality from 1 to 100. JPEG has no alpha channel, so
+    /// images are flattened over an opaque background color.
+    Jpeg {
+        quality: u8,
+        background: [u8; 3],
+    },
+    WebP(WebpCompression),
+    Bmp,
+    Qoi,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        Self::Png(BitDepth::default())
+    }
+}
+
+impl OutputFormat {
+    /// JPEG at a quality of 90 over a white background.
+    pub const JPEG: Self = Self::Jpeg {
+        quality: 90,
+        background: [255, 255, 255],
+    };
+
+    /// File extension of this format, without the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png(_) => "png",
+            Self::Tiff(..) => "tiff",
+            Self::OpenExr => "exr",
+            Self::Jpeg { .. } => "jpg",
+            Self::WebP(_) => "webp",
+            Self::Bmp => "bmp",
+            Self::Qoi => "qoi",
+        }
+    }
+
+    /// Encode an exported image in this format.
+    ///
+    /// Colors are expected to have straight alpha, as exported with
+    /// [`AlphaMode::Straight`], whichever alpha the format stores.
+    pub fn encode(&self, image: &DynamicImage) -> Result<Vec<u8>, ProcreateError> {
+        let mut buf = Cursor::new(Vec::new());
+
+        let (image, format) = match self {
+            Self::Png(depth) => (with_depth(image, *depth), ImageOutputFormat::Png),
+            Self::Tiff(depth, compression) => {
+                return encode_tiff(&with_depth(image, *depth), *compression)
+            }
+            Self::OpenExr => {
+                let mut image = image.to_rgba32f();
+                image.par_chunks_exact_mut(4).for_each(premultiply_linear);
+                (
+                    DynamicImage::ImageRgba32F(image),
+                    ImageOutputFormat::OpenExr,
+                )
+            }
+            Self::Jpeg {
+                quality,
+                background,
+            } => (
+                flatten(image, *background),
+                ImageOutputFormat::Jpeg((*quality).clamp(1, 100)),
+            ),
+            Self::WebP(compression) => return encode_webp(image, *compression),
+            Self::Bmp => (
+                DynamicImage::ImageRgba8(image.to_rgba8()),
+                ImageOutputFormat::Bmp,
+            ),
+            Self::Qoi => (
+                DynamicImage::ImageRgba8(image.to_rgba8()),
+                ImageOutputFormat::Qoi,
+            ),
+        };
+
+        image.write_to(&mut buf, format)?;
+        Ok(buf.into_inner())
+    }
+}
+
+/// Blend a straight alpha image over an opaque background color.
+fn flatten(image: &DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let image = image.to_rgba8();
+    let mut flat = RgbImage::new(image.width(), image.height());
+    flat.par_chunks_exact_mut(3)
+        .zip(image.par_chunks_exact(4))
+        .for_each(|(out, pixel)| {
+            let alpha = u32::from(pixel[3]);
+            for c in 0..3 {
+                let fg = u32::from(pixel[c]) * alpha;
+                let bg = u32::from(background[c]) * (255 - alpha);
+                out[c] = ((fg + bg + 127) / 255) as u8;
+            }
+        });
+    DynamicImage::ImageRgb8(flat)
+}
+
+/// Encode an 8 or 16-bit image as a TIFF image.
+fn encode_tiff(
+    image: &DynamicImage,
+    compression: TiffCompression,
+) -> Result<Vec<u8>, ProcreateError> {
+    use tiff::encoder::colortype::{RGBA16, RGBA8};
+
+    let mut buf = Cursor::new(Vec::new());
+    let mut encoder = TiffEncoder::new(&mut buf)?;
+    let (width, height) = image.dimensions();
+    match image {
+        DynamicImage::ImageRgba16(image) => {
+            write_tiff_image::<RGBA16, _>(&mut encoder, width, height, image, compression, |_| {
+                Ok(())
+            })?
+        }
+        image => {
+            let image = image.to_rgba8();
+            write_tiff_image::<RGBA8, _>(&mut encoder, width, height, &image, compression, |_| {
+                Ok(())
+            })?
+        }
+    }
+    Ok(buf.into_inner())
+}
+
+/// Write one image into a TIFF file, with the given compression. `tags`
+/// can add tags of its own to the image directory.
+pub(crate) fn write_tiff_image<C, W>(
+    encoder: &mut TiffEncoder<W>,
+    width: u32,
+    height: u32,
+    data: &[C::Inner],
+    compression: TiffCompression,
+    tags: impl FnOnce(&mut DirectoryEncoder<W, TiffKindStandard>) -> TiffResult<()>,
+) -> TiffResult<()>
+where
+    C: tiff::encoder::colortype::ColorType,
+    W: std::io::Write + std::io::Seek,
+    [C::Inner]: tiff::encoder::TiffValue,
+{
+    use tiff::encoder::compression::{Deflate, Lzw, Packbits, Uncompressed};
+
+    macro_rules! write_with {
+        ($compression:expr) => {{
+            let mut image =
+                encoder.new_image_with_compression::<C, _>(width, height, $compression)?;
+            tags(image.encoder())?;
+            image.write_data(data)
+        }};
+    }
+
+    match compression {
+        TiffCompression::None => write_with!(Uncompressed),
+        TiffCompression::Lzw => write_with!(Lzw),
+        TiffCompression::Deflate => write_with!(Deflate::default()),
+        TiffCompression::PackBits => write_with!(Packbits),
+    }
+}
+
+/// Encode an image as a WebP image.
+fn encode_webp(
+    image: &DynamicImage,
+    compression: WebpCompression,
+) -> Result<Vec<u8>, ProcreateError> {
+    let image = image.to_rgba8();
+    let mut buf = Vec::new();
+
+    match compression {
+        WebpCompression::Lossless => {
+            image_webp::WebPEncoder::new(&mut buf).encode(
+                &image,
+                image.width(),
+                image.height(),
+                image_webp::ColorType::Rgba8,
+            )?;
+        }
+        #[cfg(feature = "webp-lossy")]
+        WebpCompression::Lossy(quality) => {
+            use image::codecs::webp::{WebPEncoder, WebPQuality};
+            WebPEncoder::new_with_quality(&mut buf, WebPQuality::lossy(quality)).encode(
+                &image,
+                image.width(),
+                image.height(),
+                image::ColorType::Rgba8,
+            )?;
+        }
+        #[cfg(not(feature = "webp-lossy"))]
+        WebpCompression::Lossy(_) => {
+            return Err(ProcreateError::Unsupported(
+                "lossy WebP needs the webp-lossy feature",
+            ))
+        }
+    }
+    Ok(buf)
+}
+
+/// Convert an image to the given integer bit depth.
+fn with_depth(image: &DynamicImage, depth: BitDepth) -> DynamicImage {
+    match depth {
+        BitDepth::Eight => DynamicImage::ImageRgba8(image.to_rgba8()),
+        BitDepth::Sixteen => DynamicImage::ImageRgba16(image.to_rgba16()),
+    }
+}
+
+/// Decode an sRGB encoded channel into linear light.
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert a straight alpha sRGB pixel into a premultiplied linear one.
+fn premultiply_linear(pixel: &mut [f32]) {
+    let alpha = pixel[3];
+    for channel in &mut pixel[..3] {
+        *channel = srgb_to_linear(*channel) * alpha;
+    }
+}
+
+/// Area covered by an exported image on the canvas, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// Area covering the whole of an image.
+    pub fn of_image(image: &DynamicImage) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: image.width(),
+            height: image.height(),
+        }
+    }
+}
+
+/// Bounds of the pixels of an image that are not fully transparent, or
+/// `None` if the whole image is transparent.
+pub fn content_bounds(image: &DynamicImage) -> Option<Rect> {
+    let (width, height) = image.dimensions();
+    match image {
+        DynamicImage::ImageRgba8(image) => alpha_bounds(width, height, image, |a| a != 0),
+        DynamicImage::ImageRgba16(image) => alpha_bounds(width, height, image, |a| a != 0),
+        DynamicImage::ImageRgba32F(image) => alpha_bounds(width, height, image, |a| a != 0.0),
+        image => content_bounds(&DynamicImage::ImageRgba8(image.to_rgba8())),
+    }
+}
+
+/// Bounds of the RGBA pixels whose alpha is `covered`.
+fn alpha_bounds<T: Copy + Sync>(
+    width: u32,
+    height: u32,
+    pixels: &[T],
+    covered: impl Fn(T) -> bool + Sync,
+) -> Option<Rect> {
+    let stride = width as usize * 4;
+    let alpha = |x: u32, y: u32| pixels[y as usize * stride + x as usize * 4 + 3];
+    let opaque_row = |y: u32| {
+        pixels[y as usize * stride..][..stride]
+            .chunks_exact(4)
+            .any(|pixel| covered(pixel[3]))
+    };
+    let opaque_column =
+        |x: u32, top: u32, bottom: u32| (top..=bottom).any(|y| covered(alpha(x, y)));
+
+    let top = (0..height).into_par_iter().find_first(|&y| opaque_row(y))?;
+    let bottom = (top..height)
+        .into_par_iter()
+        .find_last(|&y| opaque_row(y))?;
+    let left = (0..width)
+        .into_par_iter()
+        .find_first(|&x| opaque_column(x, top, bottom))?;
+    let right = (left..width)
+        .into_par_iter()
+        .find_last(|&x| opaque_column(x, top, bottom))?;
+
+    Some(Rect {
+        x: left,
+        y: top,
+        width: right - left + 1,
+        height: bottom - top + 1,
+    })
+}
+
+/// Crop an image to the bounds of its content, and return where the
+/// cropped image sits within the original one. Images without any content
+/// are cropped to their top left transparent pixel, since most formats
+/// cannot store empty images.
+pub fn trim(image: &DynamicImage) -> (DynamicImage, Rect) {
+    let bounds = content_bounds(image).unwrap_or(Rect {
+        x: 0,
+        y: 0,
+        width: image.width().min(1),
+        height: image.height().min(1),
+    });
+    let cropped = image.crop_imm(bounds.x, bounds.y, bounds.width, bounds.height);
+    (cropped, bounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, Rgba32FImage, RgbaImage};
+
+    #[test]
+    fn trim_to_content() {
+        let mut image = RgbaImage::new(8, 6);
+        image.put_pixel(2, 1, Rgba([255, 0, 0, 255]));
+        image.put_pixel(5, 3, Rgba([0, 0, 0, 1]));
+
+        let image = DynamicImage::ImageRgba8(image);
+        let (trimmed, bounds) = trim(&image);
+        let expected = Rect {
+            x: 2,
+            y: 1,
+            width: 4,
+            height: 3,
+        };
+        assert_eq!(bounds, expected);
+        assert_eq!(trimmed.dimensions(), (4, 3));
+        let float = DynamicImage::ImageRgba32F(image.to_rgba32f());
+        assert_eq!(content_bounds(&float), Some(expected));
+    }
+
+    #[test]
+    fn encode_formats() {
+        let mut image = RgbaImage::new(5, 3);
+        image.put_pixel(1, 1, Rgba([255, 0, 0, 128]));
+        let image = DynamicImage::ImageRgba8(image);
+
+        let formats = [
+            OutputFormat::Png(BitDepth::Sixteen),
+            OutputFormat::Tiff(BitDepth::Eight, TiffCompression::Lzw),
+            OutputFormat::Tiff(BitDepth::Sixteen, TiffCompression::Deflate),
+            OutputFormat::JPEG,
+            OutputFormat::Bmp,
+            OutputFormat::Qoi,
+        ];
+        for format in formats {
+            let buf = format.encode(&image).unwrap();
+            let decoded = image::load_from_memory(&buf).unwrap();
+            assert_eq!(decoded.dimensions(), (5, 3), "{format:?}");
+        }
+
+        let webp = OutputFormat::WebP(WebpCompression::Lossless);
+        assert!(webp.encode(&image).unwrap().starts_with(b"RIFF"));
+    }
+
+    #[test]
+    fn encode_premultiplied_linear_exr() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([255, 0, 0, 51]));
+        image.put_pixel(1, 0, Rgba([188, 188, 188, 255]));
+        let buf = OutputFormat::OpenExr
+            .encode(&DynamicImage::ImageRgba8(image))
+            .unwrap();
+
+        let decoded = image::load_from_memory(&buf).unwrap().to_rgba32f();
+        let [r, g, _, a] = decoded.get_pixel(0, 0).0;
+        assert!((r - 0.2).abs() < 1e-3 && g == 0.0 && (a - 0.2).abs() < 1e-3);
+        let gray = decoded.get_pixel(1, 0).0[0];
+        assert!((gray - srgb_to_linear(188.0 / 255.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn flatten_over_background() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([0, 0, 0, 255]));
+        image.put_pixel(1, 0, Rgba([0, 0, 0, 0]));
+        let flat = flatten(&DynamicImage::ImageRgba8(image), [255, 255, 255]).to_rgb8();
+        assert_eq!(flat.as_raw(), &[0, 0, 0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn unpremultiply_edges() {
+        // Fully transparent pixels are left alone rather than divided by 0.
+        let mut image = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            Rgba([10, 20, 30, [0, 255][x as usize]])
+        }));
+        AlphaMode::Straight.apply(&mut image);
+        assert_eq!(
+            image.to_rgba8().into_raw(),
+            [10, 20, 30, 0, 10, 20, 30, 255]
+        );
+
+        let mut image = DynamicImage::ImageRgba32F(Rgba32FImage::from_fn(2, 1, |x, _| {
+            Rgba([0.25, 0.5, 0.75, [0.0, 1.0][x as usize]])
+        }));
+        AlphaMode::Straight.apply(&mut image);
+        assert_eq!(
+            image.to_rgba32f().into_raw(),
+            [0.25, 0.5, 0.75, 0.0, 0.25, 0.5, 0.75, 1.0]
+        );
+    }
+
+    #[test]
+    fn unpremultiply_round_trip() {
+        for alpha in [128u8, 200] {
+            let straight = RgbaImage::from_fn(256, 1, |x, _| Rgba([x as u8, 0, 255, alpha]));
+            let mut premultiplied = straight.clone();
+            for pixel in premultiplied.pixels_mut() {
+                for channel in &mut pixel.0[..3] {
+                    *channel = ((u32::from(*channel) * u32::from(alpha) + 127) / 255) as u8;
+                }
+            }
+            let mut image = DynamicImage::ImageRgba8(premultiplied);
+            AlphaMode::Straight.apply(&mut image);
+            for (a, b) in image.to_rgba8().pixels().zip(straight.pixels()) {
+                assert_eq!(a[3], b[3]);
+                for c in 0..3 {
+                    assert!(a[c].abs_diff(b[c]) <= 1, "{a:?} {b:?}");
+                }
+            }
+        }
+
+        let mut image =
+            DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(1, 1, Rgba([0.1, 0.2, 0.3, 0.4])));
+        AlphaMode::Straight.apply(&mut image);
+        let pixel = image.to_rgba32f().get_pixel(0, 0).0;
+        for (a, b) in pixel.iter().zip([0.25, 0.5, 0.75, 0.4]) {
+            assert!((a - b).abs() < 1e-6, "{pixel:?}");
+        }
+
+        // Premultiplied images are already what the compositor renders.
+        let mut image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([1, 2, 3, 4])));
+        AlphaMode::Premultiplied.apply(&mut image);
+        assert_eq!(image.to_rgba8().into_raw(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn trim_empty_image() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(8, 6));
+        assert_eq!(content_bounds(&image), None);
+        assert_eq!(trim(&image).0.dimensions(), (1, 1));
+    }
+}
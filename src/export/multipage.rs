@@ -0,0 +1,229 @@
+//! Multi-page TIFF export, with the composite on the first page and every
+//! layer on a page of its own after it.
+
+use super::{with_depth, write_tiff_image, BitDepth, Rect, TiffCompression};
+use crate::app::ExportedImage;
+use crate::procreate::ProcreateError;
+use image::DynamicImage;
+use std::borrow::Cow;
+use std::io::{Seek, Write};
+use tiff::encoder::colortype::{RGBA16, RGBA8};
+use tiff::encoder::{DirectoryEncoder, TiffEncoder, TiffKindStandard, TiffValue};
+use tiff::tags::{Tag, Type};
+use tiff::TiffResult;
+
+/// TIFF tags the tiff crate has no name for.
+const PAGE_NAME: Tag = Tag::Unknown(285);
+const ICC_PROFILE: Tag = Tag::Unknown(34675);
+
+/// `ExtraSamples` value of an alpha channel that colors are not multiplied by.
+const UNASSOCIATED_ALPHA: u16 = 2;
+/// `NewSubfileType` flag of a page of a multi-page image.
+const PAGE: u32 = 2;
+
+/// Writer of a multi-page TIFF file, one page at a time.
+///
+/// Images are expected to have straight alpha, as exported with
+/// [`super::AlphaMode::Straight`]. Every page covers the whole canvas, so
+/// trimmed layer images are put back where they belong on it.
+pub struct MultiPageTiff<W: Write + Seek> {
+    encoder: TiffEncoder<W>,
+    width: u32,
+    height: u32,
+    depth: BitDepth,
+    compression: TiffCompression,
+    icc_profile: Option<Vec<u8>>,
+    pages: usize,
+}
+
+impl<W: Write + Seek> MultiPageTiff<W> {
+    /// Start writing a TIFF file for a canvas of `width` by `height` pixels.
+    pub fn new(writer: W, width: u32, height: u32) -> Result<Self, ProcreateError> {
+        Ok(Self {
+            encoder: TiffEncoder::new(writer)?,
+            width,
+            height,
+            depth: BitDepth::default(),
+            compression: TiffCompression::default(),
+            icc_profile: None,
+            pages: 0,
+        })
+    }
+
+    /// Bit depth of the pages written from now on.
+    pub fn depth(mut self, depth: BitDepth) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Compression of the pages written from now on.
+    pub fn compression(mut self, compression: TiffCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// ICC profile embedded in every page written from now on, describing
+    /// the color space of the images.
+    pub fn icc_profile(mut self, profile: Vec<u8>) -> Self {
+        self.icc_profile = Some(profile);
+        self
+    }
+
+    /// Write the composite of the whole canvas, which has to be the first
+    /// page of the file.
+    pub fn write_composite(
+        &mut self,
+        image: &DynamicImage,
+        name: Option<&str>,
+    ) -> Result<(), ProcreateError> {
+        if self.pages > 0 {
+            return Err(ProcreateError::Unsupported(
+                "the composite has to be the first TIFF page",
+            ));
+        }
+        self.write_page(image, Rect::of_image(image), name)
+    }
+
+    /// Write an exported layer image as the next page, named after the layer.
+    pub fn push(&mut self, image: &ExportedImage) -> Result<(), ProcreateError> {
+        self.write_page(&image.image, image.bounds, image.layer.name.as_deref())
+    }
+
+    fn write_page(
+        &mut self,
+        image: &DynamicImage,
+        bounds: Rect,
+        name: Option<&str>,
+    ) -> Result<(), ProcreateError> {
+        let mut canvas = match self.depth {
+            BitDepth::Eight => DynamicImage::new_rgba8(self.width, self.height),
+            BitDepth::Sixteen => DynamicImage::new_rgba16(self.width, self.height),
+        };
+        image::imageops::replace(
+            &mut canvas,
+            &with_depth(image, self.depth),
+            bounds.x.into(),
+            bounds.y.into(),
+        );
+
+        // TIFF text is ASCII only.
+        let name: Option<String> = name.map(|name| {
+            name.chars()
+                .map(|c| {
+                    if c.is_ascii() && !c.is_ascii_control() {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect()
+        });
+        let icc_profile = self.icc_profile.as_deref();
+        let tags = |dir: &mut DirectoryEncoder<W, TiffKindStandard>| -> TiffResult<()> {
+            dir.write_tag(Tag::NewSubfileType, PAGE)?;
+            dir.write_tag(Tag::ExtraSamples, UNASSOCIATED_ALPHA)?;
+            if let Some(name) = &name {
+                dir.write_tag(PAGE_NAME, name.as_str())?;
+            }
+            if let Some(profile) = icc_profile {
+                dir.write_tag(ICC_PROFILE, Undefined(profile))?;
+            }
+            Ok(())
+        };
+
+        let (width, height) = (self.width, self.height);
+        let encoder = &mut self.encoder;
+        match &canvas {
+            DynamicImage::ImageRgba16(canvas) => write_tiff_image::<RGBA16, _>(
+                encoder,
+                width,
+                height,
+                canvas,
+                self.compression,
+                tags,
+            )?,
+            canvas => {
+                let canvas = canvas.to_rgba8();
+                write_tiff_image::<RGBA8, _>(
+                    encoder,
+                    width,
+                    height,
+                    &canvas,
+                    self.compression,
+                    tags,
+                )?
+            }
+        }
+        self.pages += 1;
+        Ok(())
+    }
+}
+
+/// Opaque bytes, such as an ICC profile, which TIFF stores with the
+/// `UNDEFINED` type rather than as numbers.
+struct Undefined<'a>(&'a [u8]);
+
+impl TiffValue for Undefined<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: Type = Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::fixtures::exported;
+    use image::{Rgba, RgbaImage};
+    use std::io::Cursor;
+    use tiff::decoder::Decoder;
+
+    #[test]
+    fn write_pages() {
+        let mut buf = Cursor::new(Vec::new());
+        let mut tiff = MultiPageTiff::new(&mut buf, 4, 3)
+            .unwrap()
+            .compression(TiffCompression::Lzw)
+            .icc_profile(vec![1, 2, 3]);
+        let composite = DynamicImage::ImageRgba8(RgbaImage::new(4, 3));
+        tiff.write_composite(&composite, Some("Composite")).unwrap();
+
+        let mut layer = RgbaImage::new(1, 1);
+        layer.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        let layer = ExportedImage {
+            image: DynamicImage::ImageRgba8(layer),
+            bounds: Rect {
+                x: 2,
+                y: 1,
+                width: 1,
+                height: 1,
+            },
+            ..exported(0, Some("Ink ✏"), &[])
+        };
+        tiff.push(&layer).unwrap();
+        assert!(tiff.write_composite(&composite, None).is_err());
+
+        buf.set_position(0);
+        let mut decoder = Decoder::new(buf).unwrap();
+        assert_eq!(
+            decoder.get_tag_ascii_string(PAGE_NAME).unwrap(),
+            "Composite"
+        );
+        assert!(decoder.more_images());
+        decoder.next_image().unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (4, 3));
+        assert_eq!(decoder.get_tag_ascii_string(PAGE_NAME).unwrap(), "Ink _");
+        assert_eq!(decoder.get_tag_u8_vec(ICC_PROFILE).unwrap(), [1, 2, 3]);
+        let tiff::decoder::DecodingResult::U8(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected 8-bit pixels");
+        };
+        assert_eq!(pixels[(4 + 2) * 4..][..4], [255, 0, 0, 255]);
+        assert!(!decoder.more_images());
+    }
+}
@@ -0,0 +1,241 @@
+//! File name templates for exported layer images.
+
+use crate::app::ExportedImage;
+use crate::procreate::ProcreateError;
+use std::collections::HashSet;
+
+/// Value substituted into a [`NameTemplate`] placeholder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// Document name.
+    Doc,
+    /// Layer name.
+    Name,
+    Uuid,
+    /// Index in the linearized layer list.
+    Index,
+    /// Group path, as nested directories.
+    Group,
+    /// File extension of the output format.
+    Ext,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field { field: Field, width: usize },
+}
+
+/// Template of exported file names, such as
+/// `{doc}/{group}/{index:03}_{name}.{ext}`.
+///
+/// Placeholders are `{doc}`, `{name}`, `{uuid}`, `{index}`, `{group}` and
+/// `{ext}`. A width such as `{index:03}` pads the value with zeros, or
+/// with spaces for text values. `{{` and `}}` stand for literal braces.
+/// Substituted values are sanitised so that they can neither introduce
+/// directories nor characters that are unsafe in file names, except
+/// `{group}` which expands to one directory per group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    segments: Vec<Segment>,
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        Self::parse("image_{index}.{ext}").unwrap()
+    }
+}
+
+impl std::str::FromStr for NameTemplate {
+    type Err = ProcreateError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        Self::parse(template)
+    }
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self, ProcreateError> {
+        let invalid = || ProcreateError::InvalidTemplate(template.to_string());
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(invalid)?;
+                    let (name, width) = match rest[..end].split_once(':') {
+                        Some((name, width)) => (name, width.parse().map_err(|_| invalid())?),
+                        None => (&rest[..end], 0),
+                    };
+                    let field = match name {
+                        "doc" => Field::Doc,
+                        "name" => Field::Name,
+                        "uuid" => Field::Uuid,
+                        "index" => Field::Index,
+                        "group" => Field::Group,
+                        "ext" => Field::Ext,
+                        _ => return Err(invalid()),
+                    };
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field { field, width });
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(invalid()),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+
+    /// File name of an exported image. Empty directories, such as the
+    /// group path of a layer outside of any group, are left out.
+    pub fn render(&self, doc: Option<&str>, image: &ExportedImage, ext: &str) -> String {
+        let mut path = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => path.push_str(literal),
+                Segment::Field { field, width } => {
+                    let value = match field {
+                        Field::Doc => sanitise(doc.unwrap_or("Untitled Artwork")),
+                        Field::Name => match &image.layer.name {
+                            Some(name) => sanitise(name),
+                            None => format!("Layer {}", image.index + 1),
+                        },
+                        Field::Uuid => sanitise(&image.layer.uuid),
+                        Field::Index => format!("{:0width$}", image.index),
+                        Field::Group => image
+                            .layer
+                            .group_path
+                            .iter()
+                            .map(|group| sanitise(group))
+                            .collect::<Vec<_>>()
+                            .join("/"),
+                        Field::Ext => sanitise(ext),
+                    };
+                    path.push_str(&format!("{value:width$}"));
+                }
+            }
+        }
+
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Make a template value safe to use as a single file name component.
+fn sanitise(value: &str) -> String {
+    let value = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    // Leading dots would hide files or walk up directories, and Windows
+    // drops trailing dots and spaces.
+    let value = value.trim_start_matches('.').trim_end_matches(['.', ' ']);
+    if value.trim().is_empty() {
+        String::from("_")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Names exported files from a [`NameTemplate`], making sure that no two
+/// files get the same name.
+#[derive(Debug, Clone, Default)]
+pub struct FileNamer {
+    template: NameTemplate,
+    /// Names given out so far, lowercased since file systems are often
+    /// case insensitive.
+    used: HashSet<String>,
+}
+
+impl FileNamer {
+    pub fn new(template: NameTemplate) -> Self {
+        Self {
+            template,
+            used: HashSet::new(),
+        }
+    }
+
+    /// Name of the next exported file. Names that were already given out
+    /// get a ` (1)`, ` (2)`... suffix before their extension.
+    pub fn name(&mut self, doc: Option<&str>, image: &ExportedImage, ext: &str) -> String {
+        let path = self.template.render(doc, image, ext);
+        if self.used.insert(path.to_lowercase()) {
+            return path;
+        }
+
+        let file_start = path.rfind('/').map_or(0, |slash| slash + 1);
+        let (stem, extension) = match path[file_start..].rfind('.') {
+            Some(dot) if dot > 0 => path.split_at(file_start + dot),
+            _ => (path.as_str(), ""),
+        };
+        (1..)
+            .map(|n| format!("{stem} ({n}){extension}"))
+            .find(|candidate| self.used.insert(candidate.to_lowercase()))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::fixtures::exported;
+
+    #[test]
+    fn render_template() {
+        let template = NameTemplate::parse("{doc}/{group}/{index:03}_{name}.{ext}").unwrap();
+        let image = exported(7, Some("Line: art?"), &["Characters", "../Hero"]);
+        assert_eq!(
+            template.render(Some("My Doc"), &image, "png"),
+            "My Doc/Characters/_Hero/007_Line_ art_.png"
+        );
+
+        let image = exported(2, None, &[]);
+        assert_eq!(
+            template.render(None, &image, "png"),
+            "Untitled Artwork/002_Layer 3.png"
+        );
+    }
+
+    #[test]
+    fn parse_invalid_template() {
+        assert!(NameTemplate::parse("{layer}.png").is_err());
+        assert!(NameTemplate::parse("{index.png").is_err());
+        assert!(NameTemplate::parse("{index:x}.png").is_err());
+        assert!(NameTemplate::parse("{{index}}.png").is_ok());
+    }
+
+    #[test]
+    fn deduplicate_names() {
+        let mut namer = FileNamer::new(NameTemplate::parse("{name}.{ext}").unwrap());
+        let names = ["Sketch", "sketch", "Sketch"]
+            .map(|name| namer.name(None, &exported(0, Some(name), &[]), "png"));
+        assert_eq!(names, ["Sketch.png", "sketch (1).png", "Sketch (2).png"]);
+    }
+}
@@ -0,0 +1,279 @@
+//! Layered OpenEXR export. Every layer is stored as its own part of a
+//! multi-part EXR image, named after the layer and only covering its
+//! pixels, after a part holding the composite of the whole canvas.
+
+use super::{premultiply_linear, Rect};
+use crate::app::ExportedImage;
+use crate::procreate::ProcreateError;
+use exr::prelude::{
+    f16, AnyChannel, AnyChannels, Encoding, FlatSamples, Image, ImageAttributes, Layer,
+    LayerAttributes, Vec2, WritableImage,
+};
+use image::error::{ImageError, ParameterError, ParameterErrorKind};
+use image::DynamicImage;
+use rayon::prelude::ParallelIterator;
+use rayon::slice::ParallelSliceMut;
+use std::collections::HashSet;
+use std::io::Cursor;
+
+/// Sample type of the channels of a layered EXR image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExrPrecision {
+    /// 16-bit floats, which is plenty for 8-bit source layers.
+    #[default]
+    Half,
+    Float,
+}
+
+/// Builder of a layered EXR image, one layer at a time.
+///
+/// Images are expected to have straight sRGB colors, as exported with
+/// [`super::AlphaMode::Straight`]. They are written premultiplied and in
+/// linear light, as EXR readers expect.
+pub struct LayeredExr {
+    width: u32,
+    height: u32,
+    precision: ExrPrecision,
+    composite: Option<Layer<AnyChannels<FlatSamples>>>,
+    layers: Vec<Layer<AnyChannels<FlatSamples>>>,
+    names: HashSet<String>,
+}
+
+/// Name of the part holding the composite. Layers by that name get a
+/// number, like other duplicate names.
+const COMPOSITE: &str = "Composite";
+
+impl LayeredExr {
+    /// Create an empty EXR image for a canvas of `width` by `height` pixels.
+    pub fn new(width: u32, height: u32, precision: ExrPrecision) -> Self {
+        Self {
+            width,
+            height,
+            precision,
+            composite: None,
+            layers: Vec::new(),
+            names: HashSet::from([COMPOSITE.to_string()]),
+        }
+    }
+
+    /// Store the composite of the whole canvas, replacing any previous one.
+    pub fn set_composite(&mut self, image: &DynamicImage) -> Result<(), ProcreateError> {
+        let bounds = Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        self.composite = Some(self.layer(COMPOSITE, image, bounds)?);
+        Ok(())
+    }
+
+    /// Add an exported layer image as a new layer, on top of the previous
+    /// ones. Trimmed images are put back where they belong on the canvas.
+    /// Fails if the image does not match its bounds, or its bounds do not
+    /// fit on the canvas.
+    pub fn push(&mut self, image: &ExportedImage) -> Result<(), ProcreateError> {
+        let layer = &image.layer;
+        let fallback = if layer.group { "Group" } else { "Layer" };
+        let mut name = layer
+            .group_path
+            .iter()
+            .map(|group| layer_name_part(group, "Group"))
+            .chain([layer_name_part(
+                layer.name.as_deref().unwrap_or_default(),
+                fallback,
+            )])
+            .collect::<Vec<_>>()
+            .join(".");
+
+        if self.names.contains(&name) {
+            let base = name;
+            name = (2..)
+                .map(|n| format!("{base} ({n})"))
+                .find(|name| !self.names.contains(name))
+                .unwrap();
+        }
+
+        let part = self.layer(&name, &image.image, image.bounds)?;
+        self.names.insert(name);
+        self.layers.push(part);
+        Ok(())
+    }
+
+    /// Encode the image as an EXR file.
+    pub fn encode(self) -> Result<Vec<u8>, ProcreateError> {
+        let attributes =
+            ImageAttributes::with_size(Vec2(self.width as usize, self.height as usize));
+        let layers: Vec<_> = self.composite.into_iter().chain(self.layers).collect();
+
+        let mut buf = Cursor::new(Vec::new());
+        Image::from_layers(attributes, layers)
+            .write()
+            .to_buffered(&mut buf)?;
+        Ok(buf.into_inner())
+    }
+
+    /// Part holding the R, G, B and A channels of an image covering
+    /// `bounds` of the canvas.
+    fn layer(
+        &self,
+        name: &str,
+        image: &DynamicImage,
+        bounds: Rect,
+    ) -> Result<Layer<AnyChannels<FlatSamples>>, ProcreateError> {
+        let fits = |start: u32, size: u32, canvas: u32| {
+            start.checked_add(size).is_some_and(|end| end <= canvas)
+        };
+        if (image.width(), image.height()) != (bounds.width, bounds.height)
+            || !fits(bounds.x, bounds.width, self.width)
+            || !fits(bounds.y, bounds.height, self.height)
+        {
+            return Err(ProcreateError::Export(ImageError::Parameter(
+                ParameterError::from_kind(ParameterErrorKind::DimensionMismatch),
+            )));
+        }
+
+        let mut pixels = image.to_rgba32f().into_raw();
+        pixels.par_chunks_exact_mut(4).for_each(premultiply_linear);
+
+        let channels = ["R", "G", "B", "A"]
+            .into_iter()
+            .enumerate()
+            .map(|(channel, name)| {
+                let plane = pixels.iter().skip(channel).step_by(4).copied();
+                let samples = match self.precision {
+                    ExrPrecision::Half => FlatSamples::F16(plane.map(f16::from_f32).collect()),
+                    ExrPrecision::Float => FlatSamples::F32(plane.collect()),
+                };
+                AnyChannel::new(name, samples)
+            })
+            .collect();
+
+        Ok(Layer::new(
+            Vec2(bounds.width as usize, bounds.height as usize),
+            LayerAttributes {
+                layer_position: Vec2(bounds.x as i32, bounds.y as i32),
+                ..LayerAttributes::named(name)
+            },
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(channels),
+        ))
+    }
+}
+
+/// Part of an EXR layer name for a layer or group name. EXR separates
+/// nested layer names with dots and only stores Latin-1 text, so anything
+/// else is replaced.
+fn layer_name_part(name: &str, fallback: &str) -> String {
+    let name = name.trim();
+    if name.is_empty() {
+        return fallback.to_string();
+    }
+    name.chars()
+        .map(|c| match c {
+            '.' => '_',
+            c if u32::from(c) > 0xff || c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::fixtures::exported;
+    use exr::meta::MetaData;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn layered_parts() {
+        let mut exr = LayeredExr::new(4, 3, ExrPrecision::Half);
+        exr.set_composite(&DynamicImage::ImageRgba8(RgbaImage::new(4, 3)))
+            .unwrap();
+        let mut image = RgbaImage::new(2, 2);
+        image.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        let layer = |name, group_path| ExportedImage {
+            image: DynamicImage::ImageRgba8(image.clone()),
+            bounds: Rect {
+                x: 2,
+                y: 1,
+                width: 2,
+                height: 2,
+            },
+            ..exported(0, Some(name), group_path)
+        };
+        exr.push(&layer("Ink v1.2", &["Line art"])).unwrap();
+        exr.push(&layer("Ink v1.2", &["Line art"])).unwrap();
+        exr.push(&layer("", &[])).unwrap();
+        exr.push(&layer("Composite", &[])).unwrap();
+
+        // Each layer only stores its own pixels. Channels are sorted, so
+        // alpha comes first.
+        let FlatSamples::F16(alpha) = &exr.layers[0].channel_data.list[0].sample_data else {
+            panic!("expected half samples");
+        };
+        assert_eq!(alpha.len(), 4);
+        assert_eq!(alpha[0].to_f32(), 1.0);
+
+        let buf = exr.encode().unwrap();
+        let meta = MetaData::read_from_buffered(Cursor::new(buf), false).unwrap();
+        let parts: Vec<_> = meta
+            .headers
+            .iter()
+            .map(|header| {
+                let attributes = &header.own_attributes;
+                (
+                    attributes.layer_name.as_ref().unwrap().to_string(),
+                    attributes.layer_position,
+                    header.layer_size,
+                )
+            })
+            .collect();
+        let part =
+            |name: &str, x, y, width, height| (name.to_string(), Vec2(x, y), Vec2(width, height));
+        assert_eq!(
+            parts,
+            [
+                part("Composite", 0, 0, 4, 3),
+                part("Line art.Ink v1_2", 2, 1, 2, 2),
+                part("Line art.Ink v1_2 (2)", 2, 1, 2, 2),
+                part("Layer", 2, 1, 2, 2),
+                part("Composite (2)", 2, 1, 2, 2),
+            ]
+        );
+        assert_eq!(
+            meta.headers[0].shared_attributes.display_window.size,
+            Vec2(4, 3)
+        );
+    }
+
+    #[test]
+    fn layers_outside_canvas() {
+        let mut exr = LayeredExr::new(4, 3, ExrPrecision::Float);
+        let image = ExportedImage {
+            image: DynamicImage::ImageRgba8(RgbaImage::new(2, 2)),
+            bounds: Rect {
+                x: 3,
+                y: 0,
+                width: 2,
+                height: 2,
+            },
+            ..exported(0, Some("Ink"), &[])
+        };
+        assert!(matches!(exr.push(&image), Err(ProcreateError::Export(_))));
+        let image = ExportedImage {
+            bounds: Rect {
+                x: 5,
+                y: u32::MAX,
+                width: 2,
+                height: 2,
+            },
+            ..image
+        };
+        assert!(matches!(exr.push(&image), Err(ProcreateError::Export(_))));
+        assert!(exr.layers.is_empty());
+        assert!(exr
+            .set_composite(&DynamicImage::ImageRgba8(RgbaImage::new(2, 2)))
+            .is_err());
+    }
+}
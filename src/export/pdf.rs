@@ -0,0 +1,257 @@
+//! PDF export for proofs, with the composite on the first page and any
+//! layer or group on a page of its own after it.
+
+use super::Rect;
+use crate::app::ExportedImage;
+use crate::procreate::{ProcreateError, ProcreateFile};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::DynamicImage;
+use pdf_writer::{Content, Filter, Finish, Name, Pdf, Ref, TextStr};
+use std::io::Write;
+
+const CATALOG_ID: Ref = Ref::new(1);
+const PAGE_TREE_ID: Ref = Ref::new(2);
+const INFO_ID: Ref = Ref::new(3);
+const OUTLINE_ID: Ref = Ref::new(4);
+
+/// Resolution of PDF user space, in points per inch.
+const POINTS_PER_INCH: f32 = 72.0;
+
+/// Writer of a PDF document, one page at a time.
+///
+/// Images are expected to have straight alpha, as exported with
+/// [`super::AlphaMode::Straight`]. Every page is the size of the canvas
+/// printed at the chosen resolution, and is listed in the outline under
+/// the name of its layer.
+///
+/// Pages are not streamed: the whole document, compressed images
+/// included, is buffered in memory and nothing reaches the writer until
+/// [`PdfWriter::finish`]. Memory use grows with every page pushed, so
+/// exports of many large layers are better split over several documents.
+pub struct PdfWriter<W: Write> {
+    writer: W,
+    pdf: Pdf,
+    next_id: i32,
+    width: u32,
+    height: u32,
+    /// Size of a pixel, in points.
+    scale: f32,
+    pages: Vec<(Ref, String)>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    /// Start a PDF document for `file`, with its composite on the first
+    /// page, printed at `dpi` pixels per inch.
+    pub fn new(
+        writer: W,
+        file: &ProcreateFile,
+        composite: &DynamicImage,
+        dpi: f32,
+    ) -> Result<Self, ProcreateError> {
+        if !(dpi.is_finite() && dpi > 0.0) {
+            return Err(ProcreateError::Unsupported(
+                "the PDF resolution has to be a positive number of pixels per inch",
+            ));
+        }
+
+        let mut pdf = Pdf::new();
+        let mut info = pdf.document_info(INFO_ID);
+        if let Some(name) = &file.name {
+            info.title(TextStr(name));
+        }
+        if let Some(author) = &file.author_name {
+            info.author(TextStr(author));
+        }
+        info.creator(TextStr(env!("CARGO_PKG_NAME")));
+        info.finish();
+
+        let mut writer = Self {
+            writer,
+            pdf,
+            next_id: OUTLINE_ID.get() + 1,
+            width: composite.width(),
+            height: composite.height(),
+            scale: POINTS_PER_INCH / dpi,
+            pages: Vec::new(),
+        };
+        let name = file.name.as_deref().unwrap_or("Composite");
+        writer.write_page(name, composite, Rect::of_image(composite))?;
+        Ok(writer)
+    }
+
+    /// Add an exported layer or group image as the next page, named after
+    /// the layer. Trimmed images are put back where they belong on the
+    /// canvas. The page is kept in memory until [`PdfWriter::finish`].
+    pub fn push(&mut self, image: &ExportedImage) -> Result<(), ProcreateError> {
+        let layer = &image.layer;
+        let fallback = if layer.group { "Group" } else { "Layer" };
+        let name = layer.name.as_deref().unwrap_or(fallback);
+        self.write_page(name, &image.image, image.bounds)
+    }
+
+    /// Write the page tree and outline, then the whole document, and
+    /// return the writer.
+    pub fn finish(mut self) -> Result<W, ProcreateError> {
+        let kids = self.pages.iter().map(|(page, _)| *page);
+        self.pdf
+            .pages(PAGE_TREE_ID)
+            .kids(kids)
+            .count(self.pages.len() as i32);
+
+        let items: Vec<Ref> = (0..self.pages.len()).map(|_| self.next_ref()).collect();
+        for (i, ((page, name), item)) in self.pages.iter().zip(&items).enumerate() {
+            let mut outline_item = self.pdf.outline_item(*item);
+            outline_item.title(TextStr(name)).parent(OUTLINE_ID);
+            if i > 0 {
+                outline_item.prev(items[i - 1]);
+            }
+            if let Some(next) = items.get(i + 1) {
+                outline_item.next(*next);
+            }
+            outline_item.dest().page(*page).fit();
+        }
+        let mut outline = self.pdf.outline(OUTLINE_ID);
+        if let (Some(first), Some(last)) = (items.first(), items.last()) {
+            outline.first(*first).last(*last);
+        }
+        outline.count(items.len() as i32);
+        outline.finish();
+
+        self.pdf
+            .catalog(CATALOG_ID)
+            .pages(PAGE_TREE_ID)
+            .outlines(OUTLINE_ID);
+        self.writer.write_all(&self.pdf.finish())?;
+        Ok(self.writer)
+    }
+
+    /// Write a page showing an image that covers `bounds` of the canvas.
+    fn write_page(
+        &mut self,
+        name: &str,
+        image: &DynamicImage,
+        bounds: Rect,
+    ) -> Result<(), ProcreateError> {
+        let (page_id, content_id, image_id, mask_id) = (
+            self.next_ref(),
+            self.next_ref(),
+            self.next_ref(),
+            self.next_ref(),
+        );
+        let image_name = Name(b"Im1");
+
+        let image = image.to_rgba8();
+        let rgb: Vec<u8> = image.pixels().flat_map(|p| [p[0], p[1], p[2]]).collect();
+        let alpha: Vec<u8> = image.pixels().map(|p| p[3]).collect();
+        let opaque = alpha.iter().all(|&a| a == u8::MAX);
+
+        let rgb = deflate(&rgb)?;
+        let mut xobject = self.pdf.image_xobject(image_id, &rgb);
+        xobject.filter(Filter::FlateDecode);
+        xobject.width(image.width() as i32);
+        xobject.height(image.height() as i32);
+        xobject.color_space().device_rgb();
+        xobject.bits_per_component(8);
+        if !opaque {
+            xobject.s_mask(mask_id);
+        }
+        xobject.finish();
+
+        if !opaque {
+            let alpha = deflate(&alpha)?;
+            let mut mask = self.pdf.image_xobject(mask_id, &alpha);
+            mask.filter(Filter::FlateDecode);
+            mask.width(image.width() as i32);
+            mask.height(image.height() as i32);
+            mask.color_space().device_gray();
+            mask.bits_per_component(8);
+            mask.finish();
+        }
+
+        // PDF pages start from the bottom left corner.
+        let page_width = self.width as f32 * self.scale;
+        let page_height = self.height as f32 * self.scale;
+        let x = bounds.x as f32 * self.scale;
+        let y = page_height - (bounds.y + bounds.height) as f32 * self.scale;
+        let mut content = Content::new();
+        content.save_state();
+        content.transform([
+            bounds.width as f32 * self.scale,
+            0.0,
+            0.0,
+            bounds.height as f32 * self.scale,
+            x,
+            y,
+        ]);
+        content.x_object(image_name);
+        content.restore_state();
+        self.pdf.stream(content_id, &content.finish());
+
+        let mut page = self.pdf.page(page_id);
+        page.media_box(pdf_writer::Rect::new(0.0, 0.0, page_width, page_height));
+        page.parent(PAGE_TREE_ID);
+        page.contents(content_id);
+        page.resources().x_objects().pair(image_name, image_id);
+        page.finish();
+
+        self.pages.push((page_id, name.to_string()));
+        Ok(())
+    }
+
+    fn next_ref(&mut self) -> Ref {
+        let id = Ref::new(self.next_id);
+        self.next_id += 1;
+        id
+    }
+}
+
+/// Compress a stream with zlib, for the `FlateDecode` filter.
+fn deflate(data: &[u8]) -> Result<Vec<u8>, ProcreateError> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::fixtures::exported;
+    use crate::procreate::{fixtures, SilicaGroup};
+    use image::RgbaImage;
+
+    #[test]
+    fn pages_and_metadata() {
+        let file = ProcreateFile {
+            author_name: Some(String::from("Jo")),
+            name: Some(String::from("Proof")),
+            ..fixtures::file(SilicaGroup::empty(), 300, 150)
+        };
+        let composite =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(300, 150, image::Rgba([255; 4])));
+        assert!(PdfWriter::new(Vec::new(), &file, &composite, 0.0).is_err());
+        assert!(PdfWriter::new(Vec::new(), &file, &composite, f32::NAN).is_err());
+        let mut pdf = PdfWriter::new(Vec::new(), &file, &composite, 300.0).unwrap();
+        pdf.push(&ExportedImage {
+            image: DynamicImage::ImageRgba8(RgbaImage::new(10, 10)),
+            bounds: Rect {
+                x: 0,
+                y: 0,
+                width: 10,
+                height: 10,
+            },
+            ..exported(0, Some("Ink"), &[])
+        })
+        .unwrap();
+
+        let pdf = String::from_utf8_lossy(&pdf.finish().unwrap()).into_owned();
+        assert!(pdf.starts_with("%PDF-"));
+        assert!(pdf.contains("/Title (Proof)"));
+        assert!(pdf.contains("/Author (Jo)"));
+        assert!(pdf.contains("/Title (Ink)"));
+        assert!(pdf.contains("/Count 2"));
+        // 300 pixels at 300 DPI make an inch.
+        assert!(pdf.contains("/MediaBox [0 0 72 36]"));
+        assert_eq!(pdf.matches("/SMask").count(), 1);
+    }
+}
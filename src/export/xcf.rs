@@ -0,0 +1,579 @@
+//! GIMP XCF export, which keeps the layer hierarchy editable.
+//!
+//! XCF files are big-endian. They start with the image header and a table
+//! of pointers to every layer, followed by the layers themselves, each
+//! split into 64 by 64 tiles which are run-length encoded one channel at
+//! a time.
+
+use super::Rect;
+use crate::app::ExportedImage;
+use crate::procreate::{BlendingMode, ProcreateError, ProcreateFile};
+use crate::procreate::{SilicaGroup, SilicaHierarchy};
+use image::{Rgba, RgbaImage};
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom, Write};
+
+/// Size of the square tiles of XCF layers.
+const TILE_SIZE: u32 = 64;
+
+/// `RGBA_GIMAGE` layer type, RGB with an alpha channel.
+const RGBA_LAYER: u32 = 1;
+/// `GIMP_PRECISION_U8_NON_LINEAR`, 8-bit sRGB encoded channels.
+const PRECISION_U8_GAMMA: u32 = 150;
+
+const PROP_END: u32 = 0;
+const PROP_OPACITY: u32 = 6;
+const PROP_MODE: u32 = 7;
+const PROP_VISIBLE: u32 = 8;
+const PROP_OFFSETS: u32 = 15;
+const PROP_COMPRESSION: u32 = 17;
+const PROP_GROUP_ITEM: u32 = 29;
+const PROP_ITEM_PATH: u32 = 30;
+
+/// `COMPRESS_RLE`, the compression of every tile.
+const COMPRESS_RLE: u8 = 1;
+
+/// Which set of GIMP layer modes blending modes are mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum XcfModes {
+    /// Layer modes of GIMP 2.10 and later, which cover most blending
+    /// modes, and let groups blend like Procreate groups do.
+    #[default]
+    Default,
+    /// Legacy layer modes, for files GIMP 2.8 can open too. Blending modes
+    /// without a legacy equivalent fall back to normal.
+    Legacy,
+}
+
+impl XcfModes {
+    /// XCF version of files using these modes. Version 11 is the first one
+    /// with 64-bit pointers, and version 3 the first one with layer groups.
+    fn version(self) -> u32 {
+        match self {
+            Self::Default => 11,
+            Self::Legacy => 3,
+        }
+    }
+
+    /// GIMP layer mode of a Procreate blending mode.
+    fn layer_mode(self, blend: BlendingMode) -> u32 {
+        match self {
+            Self::Default => match blend {
+                BlendingMode::Normal => 28,
+                BlendingMode::Multiply => 30,
+                BlendingMode::Screen => 31,
+                BlendingMode::Add => 33,
+                BlendingMode::Lighten => 36,
+                BlendingMode::Exclusion => 52,
+                BlendingMode::Difference => 32,
+                BlendingMode::Subtract => 34,
+                BlendingMode::LinearBurn => 53,
+                BlendingMode::ColorDodge => 42,
+                BlendingMode::ColorBurn => 43,
+                BlendingMode::Overlay => 23,
+                BlendingMode::HardLight => 44,
+                BlendingMode::Color => 39,
+                BlendingMode::Luminosity => 40,
+                BlendingMode::Hue => 37,
+                BlendingMode::Saturation => 38,
+                BlendingMode::SoftLight => 45,
+                BlendingMode::Darken => 35,
+                BlendingMode::HardMix => 51,
+                BlendingMode::VividLight => 48,
+                BlendingMode::LinearLight => 50,
+                BlendingMode::PinLight => 49,
+                BlendingMode::LighterColor => 55,
+                BlendingMode::DarkerColor => 54,
+                BlendingMode::Divide => 41,
+            },
+            Self::Legacy => match blend {
+                BlendingMode::Multiply => 3,
+                BlendingMode::Screen => 4,
+                BlendingMode::Overlay => 5,
+                BlendingMode::Difference => 6,
+                BlendingMode::Add => 7,
+                BlendingMode::Subtract => 8,
+                BlendingMode::Darken => 9,
+                BlendingMode::Lighten => 10,
+                BlendingMode::Hue => 11,
+                BlendingMode::Saturation => 12,
+                BlendingMode::Color => 13,
+                BlendingMode::Luminosity => 14,
+                BlendingMode::Divide => 15,
+                BlendingMode::ColorDodge => 16,
+                BlendingMode::ColorBurn => 17,
+                BlendingMode::HardLight => 18,
+                BlendingMode::SoftLight => 19,
+                _ => 0,
+            },
+        }
+    }
+
+    /// GIMP layer mode of groups. Procreate blends the layers of groups
+    /// with the layers around them, which the legacy modes cannot do.
+    fn group_mode(self) -> u32 {
+        match self {
+            Self::Default => 61,
+            Self::Legacy => 0,
+        }
+    }
+}
+
+/// Writer of a GIMP XCF file.
+///
+/// Layer pixels are added one layer at a time with [`XcfWriter::push`],
+/// from images exported by [`crate::app::App::export_layer_pixels`] with
+/// straight alpha. GIMP has no clipping masks, so clipped layers are
+/// written unclipped.
+pub struct XcfWriter<W: Write + Seek> {
+    writer: W,
+    modes: XcfModes,
+    width: u32,
+    height: u32,
+    background_color: [f32; 4],
+    /// Every layer and group, in the order of the layer table: from the
+    /// top down, with the children of groups right after them.
+    items: Vec<Item>,
+    /// Index of each layer in `items`, by UUID.
+    by_uuid: HashMap<String, usize>,
+    /// Offset of each item in the file, or 0 until it is written.
+    pointers: Vec<u64>,
+    /// Offset of the layer table.
+    table: u64,
+}
+
+/// Layer or group of the layer table.
+struct Item {
+    kind: ItemKind,
+    name: String,
+    visible: bool,
+    opacity: f32,
+    mode: u32,
+    /// Index of the item within its parent, and of each of its parents
+    /// within theirs, outermost first.
+    path: Vec<u32>,
+}
+
+#[derive(PartialEq)]
+enum ItemKind {
+    Layer,
+    Group,
+    Background,
+}
+
+impl<W: Write + Seek> XcfWriter<W> {
+    /// Start writing an XCF file for `file`, whose canvas is `width` by
+    /// `height` pixels once rotated.
+    pub fn new(
+        mut writer: W,
+        file: &ProcreateFile,
+        width: u32,
+        height: u32,
+        modes: XcfModes,
+    ) -> Result<Self, ProcreateError> {
+        let mut items = Vec::new();
+        let mut by_uuid = HashMap::new();
+        collect_items(
+            &file.layers,
+            modes,
+            &mut Vec::new(),
+            &mut items,
+            &mut by_uuid,
+        );
+        // Procreate backgrounds are a plain color, so they become the
+        // bottom layer, hidden when the background is.
+        items.push(Item {
+            kind: ItemKind::Background,
+            name: String::from("Background"),
+            visible: !file.background_hidden,
+            opacity: 1.0,
+            mode: modes.layer_mode(BlendingMode::Normal),
+            path: vec![file.layers.children.len() as u32],
+        });
+
+        let mut header = XcfBuffer::new(0, modes);
+        header.bytes(format!("gimp xcf v{:03}\0", modes.version()).as_bytes());
+        header.u32(width);
+        header.u32(height);
+        // RGB base type
+        header.u32(0);
+        if modes.version() >= 4 {
+            header.u32(PRECISION_U8_GAMMA);
+        }
+        header.property(PROP_COMPRESSION, &[COMPRESS_RLE]);
+        header.property(PROP_END, &[]);
+        let table = header.position();
+        // Room for a pointer to every layer, and for the zero pointers
+        // ending the layer and channel tables.
+        for _ in 0..items.len() + 2 {
+            header.pointer(0);
+        }
+        writer.write_all(&header.buf)?;
+
+        Ok(Self {
+            writer,
+            modes,
+            width,
+            height,
+            background_color: file.background_color,
+            pointers: vec![0; items.len()],
+            items,
+            by_uuid,
+            table,
+        })
+    }
+
+    /// Write the pixels of an exported layer.
+    pub fn push(&mut self, image: &ExportedImage) -> Result<(), ProcreateError> {
+        let index = self
+            .by_uuid
+            .get(&image.layer.uuid)
+            .copied()
+            .filter(|_| !image.layer.group)
+            .ok_or(ProcreateError::Unsupported(
+                "XCF layers only take the pixels of layers of the document",
+            ))?;
+        self.write_item(index, &image.image.to_rgba8(), image.bounds)
+    }
+
+    /// Write the groups, the background and any layer whose pixels were
+    /// never pushed, which is left empty, then the layer table.
+    pub fn finish(mut self) -> Result<W, ProcreateError> {
+        let canvas = Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        for index in 0..self.items.len() {
+            if self.pointers[index] != 0 {
+                continue;
+            }
+            match self.items[index].kind {
+                ItemKind::Background => {
+                    let pixel = self
+                        .background_color
+                        .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+                    let image = RgbaImage::from_pixel(self.width, self.height, Rgba(pixel));
+                    self.write_item(index, &image, canvas)?;
+                }
+                // GIMP sizes groups after their children, and ignores
+                // their pixels.
+                ItemKind::Group => self.write_item(index, &RgbaImage::new(0, 0), canvas)?,
+                ItemKind::Layer => {
+                    let empty = Rect {
+                        width: 1,
+                        height: 1,
+                        ..canvas
+                    };
+                    self.write_item(index, &RgbaImage::new(1, 1), empty)?;
+                }
+            }
+        }
+
+        let mut table = XcfBuffer::new(self.table, self.modes);
+        for &pointer in &self.pointers {
+            table.pointer(pointer);
+        }
+        self.writer.seek(SeekFrom::Start(self.table))?;
+        self.writer.write_all(&table.buf)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        Ok(self.writer)
+    }
+
+    /// Write a layer, whose pixels cover `bounds` of the canvas, at the end
+    /// of the file.
+    fn write_item(
+        &mut self,
+        index: usize,
+        image: &RgbaImage,
+        bounds: Rect,
+    ) -> Result<(), ProcreateError> {
+        let offset = self.writer.seek(SeekFrom::End(0))?;
+        let item = &self.items[index];
+        let mut layer = XcfBuffer::new(offset, self.modes);
+
+        layer.u32(bounds.width);
+        layer.u32(bounds.height);
+        layer.u32(RGBA_LAYER);
+        layer.string(&item.name);
+
+        let opacity = (item.opacity.clamp(0.0, 1.0) * 255.0).round() as u32;
+        layer.property(PROP_OPACITY, &opacity.to_be_bytes());
+        layer.property(PROP_VISIBLE, &u32::from(item.visible).to_be_bytes());
+        layer.property(PROP_MODE, &item.mode.to_be_bytes());
+        let offsets = [bounds.x.to_be_bytes(), bounds.y.to_be_bytes()].concat();
+        layer.property(PROP_OFFSETS, &offsets);
+        if item.kind == ItemKind::Group {
+            layer.property(PROP_GROUP_ITEM, &[]);
+        }
+        let path: Vec<u8> = item.path.iter().flat_map(|i| i.to_be_bytes()).collect();
+        layer.property(PROP_ITEM_PATH, &path);
+        layer.property(PROP_END, &[]);
+
+        // Hierarchy, then no layer mask.
+        let hierarchy = layer.position() + 2 * layer.pointer_size();
+        layer.pointer(hierarchy);
+        layer.pointer(0);
+
+        layer.u32(bounds.width);
+        layer.u32(bounds.height);
+        // Bytes per pixel
+        layer.u32(4);
+        let level = layer.position() + 2 * layer.pointer_size();
+        layer.pointer(level);
+        layer.pointer(0);
+
+        layer.u32(bounds.width);
+        layer.u32(bounds.height);
+        let tiles = encode_tiles(image);
+        let mut tile = layer.position() + (tiles.len() as u64 + 1) * layer.pointer_size();
+        for data in &tiles {
+            layer.pointer(tile);
+            tile += data.len() as u64;
+        }
+        layer.pointer(0);
+        for data in &tiles {
+            layer.bytes(data);
+        }
+
+        self.writer.write_all(&layer.buf)?;
+        self.pointers[index] = offset;
+        Ok(())
+    }
+}
+
+/// Add the items of the children of a group to the layer table.
+fn collect_items(
+    group: &SilicaGroup,
+    modes: XcfModes,
+    path: &mut Vec<u32>,
+    items: &mut Vec<Item>,
+    by_uuid: &mut HashMap<String, usize>,
+) {
+    // Children are stored from the top down, like the layer table.
+    for (index, child) in group.children.iter().enumerate() {
+        path.push(index as u32);
+        match child {
+            SilicaHierarchy::Layer(layer) => {
+                by_uuid.insert(layer.uuid.clone(), items.len());
+                items.push(Item {
+                    kind: ItemKind::Layer,
+                    name: layer.name.clone().unwrap_or_else(|| String::from("Layer")),
+                    visible: !layer.hidden,
+                    opacity: layer.opacity,
+                    mode: modes.layer_mode(layer.blend),
+                    path: path.clone(),
+                });
+            }
+            SilicaHierarchy::Group(group) => {
+                items.push(Item {
+                    kind: ItemKind::Group,
+                    name: group.name.clone().unwrap_or_else(|| String::from("Group")),
+                    visible: !group.hidden,
+                    opacity: 1.0,
+                    mode: modes.group_mode(),
+                    path: path.clone(),
+                });
+                collect_items(group, modes, path, items, by_uuid);
+            }
+        }
+        path.pop();
+    }
+}
+
+/// Part of an XCF file being built in memory, which knows where in the
+/// file it goes so that it can point within itself.
+struct XcfBuffer {
+    buf: Vec<u8>,
+    offset: u64,
+    wide_pointers: bool,
+}
+
+impl XcfBuffer {
+    fn new(offset: u64, modes: XcfModes) -> Self {
+        Self {
+            buf: Vec::new(),
+            offset,
+            wide_pointers: modes.version() >= 11,
+        }
+    }
+
+    /// Offset in the file of the next byte.
+    fn position(&self) -> u64 {
+        self.offset + self.buf.len() as u64
+    }
+
+    fn pointer_size(&self) -> u64 {
+        if self.wide_pointers {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn pointer(&mut self, pointer: u64) {
+        if self.wide_pointers {
+            self.bytes(&pointer.to_be_bytes());
+        } else {
+            self.u32(pointer as u32);
+        }
+    }
+
+    /// Length prefixed, null terminated UTF-8 string.
+    fn string(&mut self, text: &str) {
+        let text = text.replace('\0', "");
+        self.u32(text.len() as u32 + 1);
+        self.bytes(text.as_bytes());
+        self.bytes(&[0]);
+    }
+
+    fn property(&mut self, kind: u32, payload: &[u8]) {
+        self.u32(kind);
+        self.u32(payload.len() as u32);
+        self.bytes(payload);
+    }
+}
+
+/// Split an image into tiles, from left to right then top to bottom, and
+/// run-length encode each of them.
+fn encode_tiles(image: &RgbaImage) -> Vec<Vec<u8>> {
+    let mut tiles = Vec::new();
+    let mut channel = Vec::new();
+    for top in (0..image.height()).step_by(TILE_SIZE as usize) {
+        for left in (0..image.width()).step_by(TILE_SIZE as usize) {
+            let width = TILE_SIZE.min(image.width() - left);
+            let height = TILE_SIZE.min(image.height() - top);
+
+            let mut tile = Vec::new();
+            for c in 0..4 {
+                channel.clear();
+                for y in top..top + height {
+                    channel.extend((left..left + width).map(|x| image.get_pixel(x, y)[c]));
+                }
+                encode_rle(&channel, &mut tile);
+            }
+            tiles.push(tile);
+        }
+    }
+    tiles
+}
+
+/// Run-length encode the bytes of one channel of a tile.
+fn encode_rle(data: &[u8], out: &mut Vec<u8>) {
+    let run_length = |start: usize| {
+        data[start..]
+            .iter()
+            .take_while(|&&byte| byte == data[start])
+            .count()
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let run = run_length(i);
+        if run >= 3 {
+            if run <= 127 {
+                out.push(run as u8 - 1);
+            } else {
+                out.push(127);
+                out.extend_from_slice(&(run as u16).to_be_bytes());
+            }
+            out.push(data[i]);
+            i += run;
+            continue;
+        }
+
+        // Literal bytes, up to the next run worth encoding.
+        let start = i;
+        while i < data.len() && run_length(i) < 3 {
+            i += 1;
+        }
+        let literal = &data[start..i];
+        if literal.len() <= 127 {
+            out.push((256 - literal.len()) as u8);
+        } else {
+            out.push(128);
+            out.extend_from_slice(&(literal.len() as u16).to_be_bytes());
+        }
+        out.extend_from_slice(literal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode the run-length encoded bytes of one channel of a tile.
+    fn decode_rle(mut data: &[u8], len: usize) -> (Vec<u8>, &[u8]) {
+        let mut out = Vec::new();
+        while out.len() < len {
+            let op = data[0];
+            data = &data[1..];
+            let long = || usize::from(u16::from_be_bytes([data[0], data[1]]));
+            match op {
+                0..=126 => {
+                    out.extend(std::iter::repeat_n(data[0], usize::from(op) + 1));
+                    data = &data[1..];
+                }
+                127 => {
+                    out.extend(std::iter::repeat_n(data[2], long()));
+                    data = &data[3..];
+                }
+                128 => {
+                    let n = long();
+                    out.extend_from_slice(&data[2..2 + n]);
+                    data = &data[2 + n..];
+                }
+                _ => {
+                    let n = 256 - usize::from(op);
+                    out.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                }
+            }
+        }
+        (out, data)
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let mut data = vec![7; 300];
+        data.extend(0..=255);
+        data.extend([1, 1, 2, 2, 2, 3]);
+        let mut encoded = Vec::new();
+        encode_rle(&data, &mut encoded);
+        let (decoded, rest) = decode_rle(&encoded, data.len());
+        assert_eq!(decoded, data);
+        assert!(rest.is_empty());
+        assert!(encoded.len() < data.len());
+    }
+
+    #[test]
+    fn planar_tiles() {
+        let mut image = RgbaImage::new(65, 2);
+        image.put_pixel(64, 1, Rgba([1, 2, 3, 4]));
+        let tiles = encode_tiles(&image);
+        assert_eq!(tiles.len(), 2);
+
+        let mut rest = tiles[1].as_slice();
+        for expected in [1, 2, 3, 4] {
+            let (channel, next) = decode_rle(rest, 2);
+            assert_eq!(channel, [0, expected]);
+            rest = next;
+        }
+    }
+
+    #[test]
+    fn legacy_modes() {
+        assert_eq!(XcfModes::Legacy.layer_mode(BlendingMode::Multiply), 3);
+        assert_eq!(XcfModes::Legacy.layer_mode(BlendingMode::VividLight), 0);
+        assert_eq!(XcfModes::Default.layer_mode(BlendingMode::Multiply), 30);
+    }
+}
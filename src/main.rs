@@ -1,68 +1,110 @@
-mod app;
-mod compositor;
 mod error;
-mod ns_archive;
-mod procreate;
 
-use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use std::fs::File;
-use std::io::Cursor;
 use std::io::Write;
 
-use app::App;
-use compositor::dev::GpuHandle;
-use image::ImageOutputFormat;
+use mica::app::{App, LayerExport};
+use mica::compositor::dev::GpuHandle;
+use mica::export::{AlphaMode, FileNamer, Manifest, NameTemplate, OutputFormat};
+use mica::procreate::{ProcreateError, ProcreateFile};
 use zip::{write::FileOptions, write::ZipWriter};
 
 #[tokio::main]
-async fn main() {
-    let current_dir = std::env::current_dir().expect("Unable to get current working directory");
+async fn main() -> Result<(), ProcreateError> {
+    // Layer images cover the whole canvas, unless trimmed to their content
+    // with `--trim`.
+    let trim = std::env::args().skip(1).any(|arg| arg == "--trim");
+
+    let current_dir = std::env::current_dir()?;
     let config_path =
         std::path::Path::new(&current_dir).join("demo_files/Reference_Blend_File.procreate");
     // let config_path =
     //     std::path::Path::new(&current_dir).join("demo_files/Untitled_Artwork.procreate");
 
-    let dev = GpuHandle::new().await.expect("Unable to create GpuHandle");
+    let dev = GpuHandle::new().await?;
+    // No logger is set up, so report the chosen adapter here.
+    let adapter = dev.adapter.get_info();
+    println!("Using adapter {} ({:?})", adapter.name, adapter.backend);
     let app = App::new(dev);
 
-    let (file, gpu_textures, target) = app
-        .load_file_from_path(config_path)
-        .await
-        .expect("Unable to load file");
+    let (file, gpu_textures, target) = app.load_file_from_path(config_path).await?;
 
     let path = std::path::Path::new("example.zip");
-    let custom_file = File::create(&path).expect("Unable to create file");
+    let custom_file = File::create(path)?;
 
     let mut zip = ZipWriter::new(custom_file);
+    let mut namer = FileNamer::new(NameTemplate::parse(
+        "{doc}/{group}/{index:03}_{name}.{ext}",
+    )?);
+    let format = OutputFormat::default();
+    let mut manifest = Manifest::new(&file, target.dim.width, target.dim.height);
+
+    // Each layer is encoded and written as soon as its batch is read back,
+    // so only one batch of layer images is held in memory at a time.
+    let mut export = app
+        .export_layers(&file, &gpu_textures, target)
+        .batched(App::EXPORT_BATCH_SIZE)
+        // PNG stores straight alpha
+        .alpha(AlphaMode::Straight)
+        .trimmed(trim);
+    write_export(
+        &mut export,
+        &mut zip,
+        &mut namer,
+        &mut manifest,
+        &file,
+        format,
+    )
+    .await?;
+
+    // Then each group flattened on its own, next to the layers.
+    let mut namer = FileNamer::new(NameTemplate::parse("{doc}/groups/{group}/{name}.{ext}")?);
+    let mut export = app
+        .export_groups(&file, &gpu_textures, export.into_target(), None, false)
+        .batched(App::EXPORT_BATCH_SIZE)
+        .alpha(AlphaMode::Straight)
+        .trimmed(trim);
+    write_export(
+        &mut export,
+        &mut zip,
+        &mut namer,
+        &mut manifest,
+        &file,
+        format,
+    )
+    .await?;
+
+    zip.start_file("manifest.json", FileOptions::default())?;
+    zip.write_all(&manifest.to_json()?)?;
+
+    zip.finish()?;
+    println!("Zip file created successfully!");
+    Ok(())
+}
 
-    let image_buffers = app
-        .extract_image_buffers(&file, &gpu_textures, target)
-        .await;
-
-    let image_buffers: Vec<Vec<u8>> = image_buffers
-        .into_par_iter()
-        .map(|image_buffer| {
-            let mut buf = Cursor::new(Vec::new());
-
-            image_buffer
-                .write_to(&mut buf, ImageOutputFormat::Png)
-                .unwrap();
+/// Encode and write every image of an export into the zip file, and record
+/// them in the manifest.
+async fn write_export(
+    export: &mut LayerExport<'_>,
+    zip: &mut ZipWriter<File>,
+    namer: &mut FileNamer,
+    manifest: &mut Manifest,
+    file: &ProcreateFile,
+    format: OutputFormat,
+) -> Result<(), ProcreateError> {
+    while let Some(layer) = export.next_layer().await {
+        let layer = layer?;
 
-            let inner_vec = buf.into_inner();
+        let buf = format.encode(&layer.image)?;
 
-            inner_vec
-        })
-        .collect();
+        let file_path = namer.name(file.name.as_deref(), &layer, format.extension());
 
-    for (index, image_buffer) in image_buffers.iter().enumerate() {
-        let file_path = format!("image_{}.png", index);
+        zip.start_file(&file_path, FileOptions::default())?;
 
-        zip.start_file(file_path, FileOptions::default()).unwrap();
+        zip.write_all(&buf[..])?;
 
-        zip.write_all(&image_buffer[..])
-            .expect("Unable to write to zip");
+        manifest.push(file_path, &layer);
     }
 
-    zip.finish().expect("Unable to finish zip");
-    println!("Zip file created successfully!");
+    Ok(())
 }
@@ -1,6 +1,7 @@
 //! # Welcome to mica!
 pub mod app;
 pub mod compositor;
+pub mod export;
 pub mod ns_archive;
 pub mod procreate;
 
@@ -4,7 +4,7 @@ use std::sync::atomic::AtomicU32;
 use super::{
     ProcreateError, SilicaGroup, SilicaHierarchy, SilicaLayer, TilingData, ZipArchiveMmap,
 };
-use crate::compositor::{dev::GpuHandle, tex::GpuTexture};
+use crate::compositor::{dev::GpuHandle, tex::LayerTextures};
 use crate::ns_archive::{NsArchiveError, NsClass, Size, WrappedArray};
 use crate::ns_archive::{NsDecode, NsKeyedArchive};
 use crate::procreate::BlendingMode;
@@ -32,7 +32,7 @@ pub(super) struct IRData<'a> {
     pub(super) size: Size<u32>,
     pub(super) file_names: &'a [&'a str],
     pub(super) render: &'a GpuHandle,
-    pub(super) gpu_textures: &'a GpuTexture,
+    pub(super) gpu_textures: &'a LayerTextures,
     pub(super) counter: &'a AtomicU32,
 }
 
@@ -55,9 +55,6 @@ impl ProcreateIRLayer<'_> {
         let coder = self.coder;
         let uuid = nka.fetch::<String>(coder, "UUID")?;
 
-        static INSTANCE: OnceCell<Regex> = OnceCell::new();
-        let index_regex = INSTANCE.get_or_init(|| Regex::new("(\\d+)~(\\d+)").unwrap());
-
         static LZO_INSTANCE: OnceCell<LZO> = OnceCell::new();
 
         let image = meta
@@ -70,15 +67,12 @@ impl ProcreateIRLayer<'_> {
             .map(|path| -> Result<(), ProcreateError> {
                 let mut archive = meta.archive.clone();
 
-                let chunk_str = &path[uuid.len()..path.find('.').unwrap_or(path.len())];
-                let captures = index_regex.captures(chunk_str).unwrap();
-                let col = captures[1].parse::<u32>().unwrap();
-                let row = captures[2].parse::<u32>().unwrap();
+                let invalid_chunk = || ProcreateError::InvalidChunk(path.to_string());
+                let (col, row) = chunk_position(path, &uuid, meta.tile)?;
 
                 let tile = meta.tile.tile_size(col, row);
 
-                // impossible
-                let mut chunk = archive.by_name(path).expect("path not inside zip");
+                let mut chunk = archive.by_name(path)?;
 
                 let mut buf = Vec::new();
                 chunk.read_to_end(&mut buf)?;
@@ -93,9 +87,12 @@ impl ProcreateIRLayer<'_> {
                     decoder.read_to_end(&mut dst)?;
                     dst
                 } else {
-                    let lzo = LZO_INSTANCE.get_or_init(|| minilzo_rs::LZO::init().unwrap());
+                    let lzo = LZO_INSTANCE.get_or_try_init(minilzo_rs::LZO::init)?;
                     lzo.decompress_safe(buf.as_slice(), data_len)?
                 };
+                if dst.len() < data_len {
+                    return Err(invalid_chunk());
+                }
 
                 meta.gpu_textures.replace(
                     meta.render,
@@ -179,6 +176,7 @@ impl<'a> ProcreateIRGroup<'a> {
         Ok(SilicaGroup {
             hidden: nka.fetch::<bool>(coder, "isHidden")?,
             name: nka.fetch::<Option<String>>(coder, "name")?,
+            uuid: nka.fetch::<Option<String>>(coder, "UUID")?,
             children: self
                 .children
                 .into_par_iter()
@@ -188,6 +186,23 @@ impl<'a> ProcreateIRGroup<'a> {
     }
 }
 
+/// Column and row of the tile stored at `path`, which is named after the
+/// layer UUID followed by `column~row` and an extension.
+fn chunk_position(path: &str, uuid: &str, tile: &TilingData) -> Result<(u32, u32), ProcreateError> {
+    static INSTANCE: OnceCell<Regex> = OnceCell::new();
+    let index_regex = INSTANCE.get_or_init(|| Regex::new("(\\d+)~(\\d+)").unwrap());
+
+    let chunk_str = &path[uuid.len()..path.find('.').unwrap_or(path.len())];
+    let invalid_chunk = || ProcreateError::InvalidChunk(path.to_string());
+    let captures = index_regex.captures(chunk_str).ok_or_else(invalid_chunk)?;
+    let col = captures[1].parse::<u32>().map_err(|_| invalid_chunk())?;
+    let row = captures[2].parse::<u32>().map_err(|_| invalid_chunk())?;
+    if col >= tile.columns || row >= tile.rows {
+        return Err(invalid_chunk());
+    }
+    Ok((col, row))
+}
+
 impl<'a> ProcreateIRHierarchy<'a> {
     pub(super) fn count_layer(&self) -> u32 {
         match self {
@@ -203,3 +218,38 @@ impl<'a> ProcreateIRHierarchy<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_names() {
+        let tile = TilingData {
+            columns: 3,
+            rows: 2,
+            diff: Size {
+                width: 0,
+                height: 0,
+            },
+            size: 256,
+        };
+        let uuid = "6C1F0E3A-52B8-4D0C-9F1E-2A7B3C4D5E6F";
+        let position = |chunk: &str| chunk_position(&format!("{uuid}/{chunk}"), uuid, &tile);
+
+        assert_eq!(position("2~1.chunk").unwrap(), (2, 1));
+        assert_eq!(position("0~0.lz4").unwrap(), (0, 0));
+        for chunk in [
+            "2-1.chunk",
+            "~1.chunk",
+            "3~0.chunk",
+            "0~2.chunk",
+            "99999999999~0.chunk",
+        ] {
+            assert!(
+                matches!(position(chunk), Err(ProcreateError::InvalidChunk(_))),
+                "{chunk}"
+            );
+        }
+    }
+}
@@ -1,7 +1,11 @@
 mod ir;
+mod select;
+
+pub use self::select::{LayerFilter, LayerSelector};
 
 use self::ir::{IRData, ProcreateIRHierarchy, ProcreateIRLayer};
-use crate::compositor::{dev::GpuHandle, tex::GpuTexture};
+use crate::compositor::dev::GpuHandle;
+use crate::compositor::tex::{GpuTexture, LayerTextures};
 use crate::ns_archive::{NsArchiveError, NsKeyedArchive, Size, WrappedArray};
 use image::EncodableLayout;
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
@@ -31,6 +35,32 @@ pub enum ProcreateError {
     NsArchiveError(#[from] NsArchiveError),
     #[error("Invalid values in file")]
     InvalidValue,
+    #[error("Invalid tile chunk name: {0}")]
+    InvalidChunk(String),
+    #[error("No suitable GPU adapter found")]
+    NoAdapter,
+    #[error("GPU device error: {0}")]
+    RequestDevice(#[from] wgpu::RequestDeviceError),
+    #[error("GPU buffer mapping error: {0}")]
+    BufferAsync(#[from] wgpu::BufferAsyncError),
+    #[error("GPU readback was interrupted")]
+    Readback,
+    #[error("Image export error: {0}")]
+    Export(#[from] image::ImageError),
+    #[error("TIFF export error: {0}")]
+    Tiff(#[from] tiff::TiffError),
+    #[error("OpenEXR export error: {0}")]
+    Exr(#[from] exr::error::Error),
+    #[error("WebP export error: {0}")]
+    WebP(#[from] image_webp::EncodingError),
+    #[error("Unsupported export: {0}")]
+    Unsupported(&'static str),
+    #[error("Invalid file name template: {0}")]
+    InvalidTemplate(String),
+    #[error("Invalid layer name pattern: {0}")]
+    InvalidPattern(#[from] regex::Error),
+    #[error("Manifest error: {0}")]
+    Manifest(#[from] serde_json::Error),
     #[error("Unknown decoding error")]
     #[allow(dead_code)]
     Unknown,
@@ -72,6 +102,12 @@ impl std::fmt::Display for BlendingMode {
     }
 }
 
+impl serde::Serialize for BlendingMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
 impl BlendingMode {
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -198,6 +234,8 @@ pub struct SilicaGroup {
     pub hidden: bool,
     pub children: Vec<SilicaHierarchy>,
     pub name: Option<String>,
+    /// `None` for the root group, which is not stored as a group.
+    pub uuid: Option<String>,
 }
 
 impl SilicaGroup {
@@ -207,8 +245,20 @@ impl SilicaGroup {
             hidden: true,
             children: Vec::new(),
             name: None,
+            uuid: None,
         }
     }
+
+    /// Number of layers in the group, including those of its subgroups.
+    pub fn layer_count(&self) -> usize {
+        self.children
+            .iter()
+            .map(|child| match child {
+                SilicaHierarchy::Layer(_) => 1,
+                SilicaHierarchy::Group(group) => group.layer_count(),
+            })
+            .sum()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -232,7 +282,7 @@ impl ProcreateFile {
     pub fn open<P: AsRef<Path>>(
         path: P,
         dev: &GpuHandle,
-    ) -> Result<(Self, GpuTexture), ProcreateError> {
+    ) -> Result<(Self, LayerTextures), ProcreateError> {
         let path_ref = path.as_ref();
         let file = OpenOptions::new().read(true).write(false).open(path_ref)?;
 
@@ -254,7 +304,7 @@ impl ProcreateFile {
     pub fn open_from_bytes(
         file_content: Vec<u8>,
         dev: &GpuHandle,
-    ) -> Result<(Self, GpuTexture), ProcreateError> {
+    ) -> Result<(Self, LayerTextures), ProcreateError> {
         let mut file = tempfile()?;
         file.write_all(file_content.as_bytes())?;
 
@@ -277,11 +327,14 @@ impl ProcreateFile {
         archive: ZipArchiveMmap<'_>,
         nka: NsKeyedArchive,
         dev: &GpuHandle,
-    ) -> Result<(Self, GpuTexture), ProcreateError> {
+    ) -> Result<(Self, LayerTextures), ProcreateError> {
         let root = nka.root()?;
 
         let size = nka.fetch::<Size<u32>>(root, "size")?;
         let tile_size = nka.fetch::<u32>(root, "tileSize")?;
+        if tile_size == 0 {
+            return Err(ProcreateError::InvalidValue);
+        }
         let columns = (size.width + tile_size - 1) / tile_size;
         let rows = (size.height + tile_size - 1) / tile_size;
 
@@ -301,7 +354,7 @@ impl ProcreateFile {
             .fetch::<WrappedArray<ProcreateIRHierarchy>>(root, "unwrappedLayers")?
             .objects;
 
-        let gpu_textures = GpuTexture::empty_layers(
+        let gpu_textures = LayerTextures::new(
             dev,
             size.width,
             size.height,
@@ -336,7 +389,7 @@ impl ProcreateFile {
                         })
                         .collect::<Result<Vec<f32>, _>>()?,
                 )
-                .unwrap(),
+                .map_err(|_| NsArchiveError::TypeMismatch("backgroundColor".to_string()))?,
                 name: nka.fetch::<Option<String>>(root, "name")?,
                 orientation: nka.fetch::<u32>(root, "orientation")?,
                 flipped: Flipped {
@@ -352,6 +405,7 @@ impl ProcreateFile {
                 layers: SilicaGroup {
                     hidden: false,
                     name: Some(String::from("Root Layer")),
+                    uuid: None,
                     children: ir_hierachy
                         .into_par_iter()
                         .map(|ir| ir.load(&ir_data))
@@ -362,3 +416,57 @@ impl ProcreateFile {
         ))
     }
 }
+
+/// Documents and layers for tests.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+    use std::hash::{Hash, Hasher};
+
+    /// Visible, opaque layer, with a UUID made from its name.
+    pub fn layer(name: &str, clipped: bool) -> SilicaHierarchy {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        name.hash(&mut hasher);
+        let hash = hasher.finish();
+        SilicaHierarchy::Layer(SilicaLayer {
+            blend: BlendingMode::Normal,
+            clipped,
+            hidden: false,
+            mask: None,
+            name: Some(name.to_string()),
+            opacity: 1.0,
+            size: Size {
+                width: 1,
+                height: 1,
+            },
+            uuid: format!(
+                "{:08X}-{:04X}-4000-8000-{:012X}",
+                hash >> 32,
+                (hash >> 16) & 0xffff,
+                hash & 0xffff_ffff_ffff
+            ),
+            version: 0,
+            image: 0,
+        })
+    }
+
+    /// Unnamed document of `width` by `height` pixels holding `layers`.
+    pub fn file(layers: SilicaGroup, width: u32, height: u32) -> ProcreateFile {
+        ProcreateFile {
+            author_name: None,
+            background_hidden: false,
+            background_color: [1.0; 4],
+            flipped: Flipped {
+                horizontally: false,
+                vertically: false,
+            },
+            layers,
+            name: None,
+            orientation: 0,
+            stroke_count: 0,
+            tile_size: 256,
+            composite: None,
+            size: Size { width, height },
+        }
+    }
+}
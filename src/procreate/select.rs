@@ -0,0 +1,241 @@
+use super::{BlendingMode, ProcreateError, SilicaGroup, SilicaHierarchy, SilicaLayer};
+use regex::Regex;
+use std::ops::Range;
+
+/// Criterion a layer has to meet to be selected by a [`LayerFilter`].
+#[derive(Debug, Clone)]
+pub enum LayerSelector {
+    /// Layer name matches a regular expression. Unnamed layers never match.
+    Name(Regex),
+    Uuid(String),
+    /// Layer is inside the group with this path of group names, outermost
+    /// first, either directly or in one of its subgroups.
+    GroupPath(Vec<String>),
+    /// Position of the layer among all layers of the document, hidden ones
+    /// included, starting at 0 for the bottom layer.
+    Index(Range<usize>),
+    /// Whether the layer and all of its groups are visible.
+    Visible(bool),
+    Blend(BlendingMode),
+}
+
+impl LayerSelector {
+    /// Select layers whose whole name matches a glob pattern, where `*`
+    /// matches any run of characters and `?` any single character.
+    pub fn name_glob(pattern: &str) -> Result<Self, ProcreateError> {
+        let mut regex = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        regex.push('$');
+        Ok(Self::Name(Regex::new(&regex)?))
+    }
+
+    /// Select layers whose name matches a regular expression anywhere.
+    pub fn name_regex(pattern: &str) -> Result<Self, ProcreateError> {
+        Ok(Self::Name(Regex::new(pattern)?))
+    }
+
+    fn matches(&self, layer: &SilicaLayer, place: &LayerPlace<'_>) -> bool {
+        match self {
+            Self::Name(regex) => layer
+                .name
+                .as_deref()
+                .is_some_and(|name| regex.is_match(name)),
+            Self::Uuid(uuid) => layer.uuid.eq_ignore_ascii_case(uuid),
+            Self::GroupPath(path) => place.group_path.starts_with(path),
+            Self::Index(range) => range.contains(&place.index),
+            Self::Visible(visible) => (place.visible && !layer.hidden) == *visible,
+            Self::Blend(blend) => layer.blend == *blend,
+        }
+    }
+}
+
+/// Where a layer sits in the document.
+struct LayerPlace<'a> {
+    group_path: &'a [String],
+    index: usize,
+    /// Whether all groups containing the layer are visible.
+    visible: bool,
+}
+
+/// Selection of the layers of a document, for exporting or compositing
+/// only some of them. A layer is selected when it meets every selector, so
+/// a filter without any selector selects every layer.
+#[derive(Debug, Clone, Default)]
+pub struct LayerFilter {
+    pub selectors: Vec<LayerSelector>,
+    /// Export hidden layers too, as if they were visible. Otherwise hidden
+    /// layers are left out even when they are selected.
+    pub include_hidden: bool,
+}
+
+impl LayerFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only select layers that also meet `selector`.
+    pub fn with(mut self, selector: LayerSelector) -> Self {
+        self.selectors.push(selector);
+        self
+    }
+
+    /// Whether to export hidden layers too. See [`LayerFilter::include_hidden`].
+    pub fn with_hidden(mut self, include_hidden: bool) -> Self {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    /// Copy of the layer tree with only the selected layers. Groups left
+    /// without any layer are removed. A selected clipped layer whose
+    /// clipping base is not selected is no longer clipped, rather than
+    /// being clipped onto whichever layer ends up below it.
+    pub fn apply(&self, layers: &SilicaGroup) -> SilicaGroup {
+        if self.selectors.is_empty() {
+            return layers.clone();
+        }
+
+        let mut state = FilterState {
+            index: 0,
+            base_selected: false,
+            group_path: Vec::new(),
+        };
+        self.filter_group(layers, true, &mut state)
+    }
+
+    fn filter_group(
+        &self,
+        group: &SilicaGroup,
+        visible: bool,
+        state: &mut FilterState,
+    ) -> SilicaGroup {
+        // Children are stored from the top down, but layers are numbered
+        // and clipped from the bottom up.
+        let mut children = Vec::new();
+        for child in group.children.iter().rev() {
+            match child {
+                SilicaHierarchy::Group(subgroup) => {
+                    state
+                        .group_path
+                        .push(subgroup.name.clone().unwrap_or_default());
+                    let subgroup = self.filter_group(subgroup, visible && !subgroup.hidden, state);
+                    state.group_path.pop();
+
+                    if !subgroup.children.is_empty() {
+                        children.push(SilicaHierarchy::Group(subgroup));
+                    }
+                }
+                SilicaHierarchy::Layer(layer) => {
+                    let place = LayerPlace {
+                        group_path: &state.group_path,
+                        index: state.index,
+                        visible,
+                    };
+                    let selected = self
+                        .selectors
+                        .iter()
+                        .all(|selector| selector.matches(layer, &place));
+                    state.index += 1;
+
+                    if !layer.clipped {
+                        state.base_selected = selected;
+                    }
+                    if selected {
+                        let mut layer = layer.clone();
+                        layer.clipped &= state.base_selected;
+                        children.push(SilicaHierarchy::Layer(layer));
+                    }
+                }
+            }
+        }
+        children.reverse();
+
+        SilicaGroup {
+            hidden: group.hidden,
+            children,
+            name: group.name.clone(),
+            uuid: group.uuid.clone(),
+        }
+    }
+}
+
+struct FilterState {
+    /// Index of the next layer, from the bottom of the document.
+    index: usize,
+    /// Whether the layer the next clipped layer clips onto is selected.
+    base_selected: bool,
+    group_path: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procreate::fixtures::layer;
+
+    fn names(group: &SilicaGroup) -> Vec<(String, bool)> {
+        group
+            .children
+            .iter()
+            .flat_map(|child| match child {
+                SilicaHierarchy::Layer(layer) => vec![(layer.name.clone().unwrap(), layer.clipped)],
+                SilicaHierarchy::Group(group) => names(group),
+            })
+            .collect()
+    }
+
+    fn document() -> SilicaGroup {
+        // Top down, as stored in documents.
+        SilicaGroup {
+            hidden: false,
+            name: None,
+            uuid: None,
+            children: vec![
+                layer("Shading", true),
+                layer("Ink", false),
+                SilicaHierarchy::Group(SilicaGroup {
+                    hidden: true,
+                    name: Some(String::from("Sketches")),
+                    uuid: None,
+                    children: vec![layer("Sketch 2", false), layer("Sketch 1", false)],
+                }),
+                layer("Paper", false),
+            ],
+        }
+    }
+
+    #[test]
+    fn select_by_glob() {
+        let filter = LayerFilter::new().with(LayerSelector::name_glob("Sketch ?").unwrap());
+        let selected = filter.apply(&document());
+        let expected = [("Sketch 2", false), ("Sketch 1", false)].map(|(n, c)| (n.to_string(), c));
+        assert_eq!(names(&selected), expected);
+    }
+
+    #[test]
+    fn select_by_place() {
+        let filter = LayerFilter::new()
+            .with(LayerSelector::Index(1..4))
+            .with(LayerSelector::Visible(false));
+        let selected = filter.apply(&document());
+        let expected = [("Sketch 2", false), ("Sketch 1", false)].map(|(n, c)| (n.to_string(), c));
+        assert_eq!(names(&selected), expected);
+
+        let filter =
+            LayerFilter::new().with(LayerSelector::GroupPath(vec![String::from("Sketches")]));
+        assert_eq!(names(&filter.apply(&document())).len(), 2);
+    }
+
+    #[test]
+    fn unclip_without_base() {
+        let filter =
+            LayerFilter::new().with(LayerSelector::name_regex("^(Shading|Paper)$").unwrap());
+        let selected = filter.apply(&document());
+        let expected = [("Shading", false), ("Paper", false)].map(|(n, c)| (n.to_string(), c));
+        assert_eq!(names(&selected), expected);
+    }
+}
@@ -96,6 +96,36 @@ fn comp(c: vec3f, a: f32) -> vec3f {
     return c * (1.0 - a);
 }
 
+// Color spaces ////////////////////////////////////////////////////////////////
+// Replaced when loading the shader. Procreate blends gamma-encoded
+// values, but linear light blending matches other linear pipelines.
+const LINEAR_BLEND: bool = false;
+
+fn srgb_to_linear(c: vec3f) -> vec3f {
+    return select(pow((c + 0.055) / 1.055, vec3(2.4)), c / 12.92, c <= vec3(0.04045));
+}
+
+fn linear_to_srgb(c: vec3f) -> vec3f {
+    return select(1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3(0.0031308));
+}
+
+// Convert premultiplied gamma-encoded colors to the blending color space.
+fn to_blend_space(c: vec4f) -> vec4f {
+    if (!LINEAR_BLEND || c.a == 0.0) {
+        return c;
+    }
+    return vec4(srgb_to_linear(clamp(c.rgb / c.a, vec3(0.0), vec3(1.0))) * c.a, c.a);
+}
+
+// Convert premultiplied colors in the blending color space back to
+// gamma-encoded colors.
+fn from_blend_space(c: vec4f) -> vec4f {
+    if (!LINEAR_BLEND || c.a == 0.0) {
+        return c;
+    }
+    return vec4(linear_to_srgb(clamp(c.rgb / c.a, vec3(0.0), vec3(1.0))) * c.a, c.a);
+}
+
 fn stdalpha(b: f32, f: f32) -> f32 {
     return b + f - b * f;
 }
@@ -245,7 +275,10 @@ var<storage, read> masks: array<u32>;
 var<storage, read> blends: array<u32>;
 @group(1) @binding(5)
 var<storage, read> opacities: array<f32>;
+@group(1) @binding(6)
+var mask_textures: texture_2d_array<f32>;
 
+// Replaced by a uniform binding when push constants are unavailable.
 var<push_constant> layer_count: i32;
 
 // Blend alpha straight colors
@@ -261,11 +294,11 @@ const MASK_NONE: u32 = 0xFFFFFFFFu;
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4f {
     // Premultiplied colors
-    var bga = textureSample(composite, splr, in.bg_coords);
+    var bga = to_blend_space(textureSample(composite, splr, in.bg_coords));
 
     for (var i: i32 = 0; i < layer_count; i++) {
-        var maska = select(textureSample(textures, splr, in.fg_coords, i32(masks[i])).a, 1.0, masks[i] == MASK_NONE);
-        var fga = textureSample(textures, splr, in.fg_coords, i32(layers[i])) * maska;
+        var maska = select(textureSample(mask_textures, splr, in.fg_coords, i32(masks[i])).a, 1.0, masks[i] == MASK_NONE);
+        var fga = to_blend_space(textureSample(textures, splr, in.fg_coords, i32(layers[i]))) * maska;
 
         // Short circuit
         // if (bga.a == 0.0) {
@@ -315,5 +348,5 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4f {
         // Compute final premultiplied colors
         bga = premultiplied_blend(bga, fga, vec4(final_pixel, fg.a));
     }
-    return bga;
+    return from_blend_space(bga);
 }